@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+use crate::history::{escape, unescape};
+use crate::lexer;
+use crate::lexer::Token;
+use crate::versioned_file::VersionedFile;
+
+const VERSION: u32 = 2;
+
+/// Alias names and the command text each of them stands for.
+pub(crate) type Aliases = HashMap<String, String>;
+
+/// The alias file starts with a version header followed by one alias per line as
+/// `<name>\t<command text>`, both escaped. Version 1 files, which stored a builtin and
+/// its arguments as `<name>\t<command>\t<arguments>`, are still read.
+pub(crate) struct AliasFile {
+    file: VersionedFile,
+}
+
+impl AliasFile {
+    pub fn new(path: PathBuf) -> AliasFile {
+        AliasFile { file: VersionedFile::new(path, "aliases") }
+    }
+
+    /// Reads the aliases from the file, skipping malformed entries with a warning. A file
+    /// written by a newer version is rejected.
+    pub fn load(&self) -> io::Result<Aliases> {
+        let (version, lines) = match self.file.read(1..=VERSION)? {
+            Some(contents) => contents,
+            None => return Ok(HashMap::new()),
+        };
+
+        let mut aliases: Aliases = HashMap::new();
+        for line in lines.iter().filter(|line| !line.is_empty()) {
+            let entry = if version == 1 { decode_v1_entry(line) } else { decode_entry(line) };
+            match entry {
+                Some((name, text)) => {
+                    aliases.insert(name, text);
+                }
+                None => eprintln!("Ignoring malformed alias entry \"{}\"", line),
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// Writes every alias to the file, replacing it as a whole.
+    pub fn save(&self, aliases: &Aliases) -> io::Result<()> {
+        let mut names: Vec<&String> = aliases.keys().collect();
+        names.sort();
+        let lines: Vec<String> = names.into_iter()
+            .map(|name| format!("{}\t{}", escape(name), escape(aliases[name].as_str())))
+            .collect();
+        self.file.write(VERSION, lines.as_slice())
+    }
+}
+
+fn decode_entry(line: &str) -> Option<(String, String)> {
+    let (name, text) = line.split_once('\t')?;
+    let name = unescape(name).filter(|name| !name.is_empty())?;
+    Some((name, unescape(text)?))
+}
+
+// A version 1 entry becomes the builtin followed by its arguments
+fn decode_v1_entry(line: &str) -> Option<(String, String)> {
+    let fields: Vec<&str> = line.splitn(3, '\t').collect();
+    if fields.len() != 3 || fields[1].is_empty() {
+        return None;
+    }
+    let name = unescape(fields[0]).filter(|name| !name.is_empty())?;
+    let text = match unescape(fields[2])? {
+        args if args.is_empty() => String::from(fields[1]),
+        // History invocations were stored apart from their number, e.g. `!` and `5`
+        args if fields[1].starts_with('!') => format!("{}{}", fields[1], args),
+        args => format!("{} {}", fields[1], args),
+    };
+    Some((name, text))
+}
+
+/// Replaces every alias in command position with the tokens of its command text, the
+/// rest of the command following as its arguments. The first word of an alias is
+/// expanded again, so aliases can refer to other aliases, except when it is the alias
+/// itself as in `alias ls 'ls -l'`. Aliases that refer back to each other are an error.
+pub(crate) fn expand_aliases(tokens: Vec<Token>, aliases: &Aliases) -> Result<Vec<Token>, String> {
+    let mut expanded: Vec<Token> = Vec::new();
+    expand_tokens(tokens, aliases, &mut Vec::new(), &mut expanded)?;
+    Ok(expanded)
+}
+
+// Expands the tokens of a line or of an alias, `chain` holding the aliases being expanded
+fn expand_tokens(tokens: Vec<Token>, aliases: &Aliases, chain: &mut Vec<String>,
+                 expanded: &mut Vec<Token>) -> Result<(), String> {
+    let mut command_position = true;
+    for token in tokens {
+        let next_command_position = starts_command(&token) || (command_position && precedes_command(&token));
+        let alias = match &token {
+            Token::Word(word) if command_position => word.as_plain()
+                .filter(|name| aliases.contains_key(*name))
+                .map(String::from),
+            _ => None,
+        };
+        command_position = next_command_position;
+        let name = match alias {
+            // An alias used as its own command is the program it shadows
+            Some(name) if chain.last() != Some(&name) => name,
+            _ => {
+                expanded.push(token);
+                continue;
+            }
+        };
+        if chain.contains(&name) {
+            return Err(format!("Alias cycle: {} -> {}", chain.join(" -> "), name));
+        }
+        let alias_tokens = lexer::tokenize(aliases[&name].as_str())
+            .map_err(|error| format!("Alias \"{}\" is malformed: {}", name, error))?;
+        chain.push(name);
+        expand_tokens(alias_tokens, aliases, chain, expanded)?;
+        chain.pop();
+    }
+    Ok(())
+}
+
+// Whether a new command starts after the token
+fn starts_command(token: &Token) -> bool {
+    matches!(token, Token::Pipe | Token::Semicolon | Token::Background | Token::And | Token::Or
+        | Token::OpenParen | Token::Newline)
+}
+
+// Whether a reserved word in command position is followed by another command, as `then` is
+fn precedes_command(token: &Token) -> bool {
+    matches!(token, Token::Word(word)
+        if matches!(word.as_plain(), Some("{" | "if" | "then" | "elif" | "else" | "while" | "until" | "do")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(definitions: &[(&str, &str)]) -> Aliases {
+        definitions.iter().map(|(name, text)| (String::from(*name), String::from(*text))).collect()
+    }
+
+    fn expand(line: &str, aliases: &Aliases) -> Result<String, String> {
+        let tokens = lexer::tokenize(line).ok().unwrap();
+        expand_aliases(tokens, aliases).map(|tokens| lexer::to_source(&tokens))
+    }
+
+    #[test]
+    fn expands_the_command_word() {
+        let aliases = aliases(&[("ll", "ls -l"), ("g", "git")]);
+        assert_eq!(expand("ll -a src", &aliases).unwrap(), "ls -l -a src");
+        assert_eq!(expand("echo ll g", &aliases).unwrap(), "echo ll g");
+    }
+
+    #[test]
+    fn expands_every_command_of_a_line() {
+        let aliases = aliases(&[("ll", "ls -l")]);
+        assert_eq!(expand("ll | ll; ll && ll || ll & ll", &aliases).unwrap(), "ls -l | ls -l; ls -l && ls -l || ls -l & ls -l");
+        assert_eq!(expand("if ll; then ll; fi", &aliases).unwrap(), "if ls -l; then ls -l; fi");
+        assert_eq!(expand("( ll )", &aliases).unwrap(), "( ls -l )");
+    }
+
+    #[test]
+    fn leaves_quoted_and_escaped_words_alone() {
+        let aliases = aliases(&[("ll", "ls -l")]);
+        assert_eq!(expand("'ll'", &aliases).unwrap(), "ll");
+        assert_eq!(expand("\\ll", &aliases).unwrap(), "ll");
+    }
+
+    #[test]
+    fn expands_aliases_of_aliases() {
+        let aliases = aliases(&[("l", "ll"), ("ll", "ls -l"), ("many", "l; l")]);
+        assert_eq!(expand("l x", &aliases).unwrap(), "ls -l x");
+        assert_eq!(expand("many", &aliases).unwrap(), "ls -l; ls -l");
+    }
+
+    #[test]
+    fn an_alias_of_itself_is_the_command_it_shadows() {
+        let aliases = aliases(&[("ls", "ls -F"), ("l", "ls")]);
+        assert_eq!(expand("ls", &aliases).unwrap(), "ls -F");
+        assert_eq!(expand("l", &aliases).unwrap(), "ls -F");
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let aliases = aliases(&[("a", "b x"), ("b", "c"), ("c", "a")]);
+        assert_eq!(expand("a", &aliases).unwrap_err(), "Alias cycle: a -> b -> c -> a");
+        assert_eq!(expand("echo; b", &aliases).unwrap_err(), "Alias cycle: b -> c -> a -> b");
+    }
+
+    #[test]
+    fn reports_malformed_aliases() {
+        let aliases = aliases(&[("q", "echo 'open")]);
+        assert!(expand("q", &aliases).unwrap_err().starts_with("Alias \"q\" is malformed"));
+    }
+}
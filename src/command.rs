@@ -1,10 +1,23 @@
-use std::fs::metadata;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
 
-use crate::terminal::Terminal;
+use crate::history::HistoryEntry;
+use crate::jobs;
+use crate::jobs::{JobState, WaitOutcome};
+use crate::lexer;
+use crate::options::ShellOption;
+use crate::pipeline;
+use crate::pipeline::Stage;
+use crate::status::Status;
+use crate::streams::{errln, outln, Streams};
+use crate::terminal::{LoopControl, Terminal};
+use crate::variables::is_valid_name;
 
 pub(crate) struct CommandWrapper {
     pub name: String,
+    #[allow(dead_code)]
     help: String,
     exec: Box<dyn CommandExecutor>,
 
@@ -15,20 +28,25 @@ impl CommandWrapper {
         CommandWrapper { name: String::from(name), help: String::from(help), exec }
     }
 
-    pub(crate) fn execute(&self, cmd_string_line: &str, terminal: &mut Terminal) -> bool {
-        let parsed_cmd_line = cmd_string_line.replace(&self.name, "");
-        let parsed_cmd_line = String::from(parsed_cmd_line.trim());
-        let parsed_cmd_option: Option<String>;
-        if parsed_cmd_line.is_empty() {
-            parsed_cmd_option = None;
-        } else {
-            parsed_cmd_option = Some(parsed_cmd_line);
+    /// Runs the command with the words of a tokenized line, the first being the command
+    /// itself. Prefix commands such as `!5` are split so the executor receives `5`.
+    pub(crate) fn execute(&self, mut words: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if words.is_empty() {
+            return self.exec.execute(words, terminal, streams);
+        }
+        let first = words.remove(0);
+        if let Some(rest) = first.strip_prefix(self.name.as_str()) {
+            if !rest.is_empty() {
+                words.insert(0, String::from(rest));
+            }
         }
-        self.exec.execute(parsed_cmd_option, terminal)
+        self.exec.execute(words, terminal, streams)
     }
 }
 
 
+// LastCommand, NCommand and NMinusCommand keep their baseline names
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Command {
     ChangeDir,
     GetPath,
@@ -41,6 +59,27 @@ pub(crate) enum Command {
     Unalias,
     // Alternative to closing
     Exit,
+    Jobs,
+    Foreground,
+    Background,
+    Wait,
+    Kill,
+    Export,
+    Unset,
+    Set,
+    Env,
+    PushDir,
+    PopDir,
+    Dirs,
+    Functions,
+    Local,
+    Return,
+    Break,
+    Continue,
+    // Program resolved against the system path
+    External(String),
+    // Function defined in the shell
+    Function(String),
 }
 
 impl PartialEq<Self> for Command {
@@ -72,7 +111,7 @@ impl Command {
 
     fn history_command() -> CommandWrapper {
         CommandWrapper::new("history",
-                            "print history contents",
+                            "print history contents | history N | -c | -d n | grep pattern | --dir [path] | -v with details | -a | -r | -n",
                             Box::new(History))
     }
 
@@ -108,10 +147,125 @@ impl Command {
 
     fn exit_command() -> CommandWrapper {
         CommandWrapper::new("exit",
-                            "close down terminal",
+                            "close down terminal, with a status if given",
                             Box::new(Exit))
     }
 
+    fn jobs_command() -> CommandWrapper {
+        CommandWrapper::new("jobs",
+                            "list background and stopped jobs",
+                            Box::new(Jobs))
+    }
+
+    fn foreground_command() -> CommandWrapper {
+        CommandWrapper::new("fg",
+                            "continue a job in the foreground",
+                            Box::new(Foreground))
+    }
+
+    fn background_command() -> CommandWrapper {
+        CommandWrapper::new("bg",
+                            "continue a stopped job in the background",
+                            Box::new(Background))
+    }
+
+    fn wait_command() -> CommandWrapper {
+        CommandWrapper::new("wait",
+                            "wait for background jobs to finish",
+                            Box::new(Wait))
+    }
+
+    fn kill_command() -> CommandWrapper {
+        CommandWrapper::new("kill",
+                            "send a signal to a job or process",
+                            Box::new(Kill))
+    }
+
+    fn export_command() -> CommandWrapper {
+        CommandWrapper::new("export",
+                            "export variables to the programs that are run",
+                            Box::new(Export))
+    }
+
+    fn unset_command() -> CommandWrapper {
+        CommandWrapper::new("unset",
+                            "remove variables",
+                            Box::new(Unset))
+    }
+
+    fn set_command() -> CommandWrapper {
+        CommandWrapper::new("set",
+                            "print all variables | set -o|+o an option",
+                            Box::new(Set))
+    }
+
+    fn env_command() -> CommandWrapper {
+        CommandWrapper::new("env",
+                            "print exported variables",
+                            Box::new(Env))
+    }
+
+    fn push_dir_command() -> CommandWrapper {
+        CommandWrapper::new("pushd",
+                            "save the working directory and change to another",
+                            Box::new(PushDir))
+    }
+
+    fn pop_dir_command() -> CommandWrapper {
+        CommandWrapper::new("popd",
+                            "change back to the last directory saved by pushd",
+                            Box::new(PopDir))
+    }
+
+    fn dirs_command() -> CommandWrapper {
+        CommandWrapper::new("dirs",
+                            "print the directory stack",
+                            Box::new(Dirs))
+    }
+
+    fn functions_command() -> CommandWrapper {
+        CommandWrapper::new("functions",
+                            "print all functions | functions name... | -d name to remove",
+                            Box::new(Functions))
+    }
+
+    fn local_command() -> CommandWrapper {
+        CommandWrapper::new("local",
+                            "make variables local to the function running",
+                            Box::new(Local))
+    }
+
+    fn return_command() -> CommandWrapper {
+        CommandWrapper::new("return",
+                            "end the function running with a status",
+                            Box::new(Return))
+    }
+
+    fn break_command() -> CommandWrapper {
+        CommandWrapper::new("break",
+                            "leave the loop running, or the given number of loops",
+                            Box::new(Break))
+    }
+
+    fn continue_command() -> CommandWrapper {
+        CommandWrapper::new("continue",
+                            "go on with the next iteration of the loop running, or of the given enclosing loop",
+                            Box::new(Continue))
+    }
+
+    fn external_command(program: &str) -> CommandWrapper {
+        CommandWrapper::new(program,
+                            "execute a program from the system path",
+                            Box::new(External { program: String::from(program) }))
+    }
+
+    fn function_command(name: &str) -> CommandWrapper {
+        CommandWrapper::new(name,
+                            "run a function defined in the shell",
+                            Box::new(CallFunction { name: String::from(name) }))
+    }
+
+    #[allow(clippy::needless_return)]
     pub fn get_command(&self) -> CommandWrapper {
         return match self {
             Command::ChangeDir => Command::change_dir_command(),
@@ -124,9 +278,29 @@ impl Command {
             Command::Alias => Command::alias_command(),
             Command::Unalias => Command::unalias_command(),
             Command::Exit => Command::exit_command(),
+            Command::Jobs => Command::jobs_command(),
+            Command::Foreground => Command::foreground_command(),
+            Command::Background => Command::background_command(),
+            Command::Wait => Command::wait_command(),
+            Command::Kill => Command::kill_command(),
+            Command::Export => Command::export_command(),
+            Command::Unset => Command::unset_command(),
+            Command::Set => Command::set_command(),
+            Command::Env => Command::env_command(),
+            Command::PushDir => Command::push_dir_command(),
+            Command::PopDir => Command::pop_dir_command(),
+            Command::Dirs => Command::dirs_command(),
+            Command::Functions => Command::functions_command(),
+            Command::Local => Command::local_command(),
+            Command::Return => Command::return_command(),
+            Command::Break => Command::break_command(),
+            Command::Continue => Command::continue_command(),
+            Command::External(program) => Command::external_command(program),
+            Command::Function(name) => Command::function_command(name),
         };
     }
 
+    #[allow(clippy::needless_return)]
     pub fn get_command_enum(cmd_string: &str) -> Option<Command> {
         return match cmd_string {
             "cd" => Some(Command::ChangeDir),
@@ -139,12 +313,29 @@ impl Command {
             "alias" => Some(Command::Alias),
             "unalias" => Some(Command::Unalias),
             "exit" => Some(Command::Exit),
+            "jobs" => Some(Command::Jobs),
+            "fg" => Some(Command::Foreground),
+            "bg" => Some(Command::Background),
+            "wait" => Some(Command::Wait),
+            "kill" => Some(Command::Kill),
+            "export" => Some(Command::Export),
+            "unset" => Some(Command::Unset),
+            "set" => Some(Command::Set),
+            "env" => Some(Command::Env),
+            "pushd" => Some(Command::PushDir),
+            "popd" => Some(Command::PopDir),
+            "dirs" => Some(Command::Dirs),
+            "functions" => Some(Command::Functions),
+            "local" => Some(Command::Local),
+            "return" => Some(Command::Return),
+            "break" => Some(Command::Break),
+            "continue" => Some(Command::Continue),
             _ => None,
         };
     }
 
     pub fn from(c: &Command) -> Command {
-        match *c {
+        match c {
             Command::ChangeDir => Command::ChangeDir,
             Command::GetPath => Command::GetPath,
             Command::SetPath => Command::SetPath,
@@ -155,9 +346,68 @@ impl Command {
             Command::Alias => Command::Alias,
             Command::Unalias => Command::Unalias,
             Command::Exit => Command::Exit,
+            Command::Jobs => Command::Jobs,
+            Command::Foreground => Command::Foreground,
+            Command::Background => Command::Background,
+            Command::Wait => Command::Wait,
+            Command::Kill => Command::Kill,
+            Command::Export => Command::Export,
+            Command::Unset => Command::Unset,
+            Command::Set => Command::Set,
+            Command::Env => Command::Env,
+            Command::PushDir => Command::PushDir,
+            Command::PopDir => Command::PopDir,
+            Command::Dirs => Command::Dirs,
+            Command::Functions => Command::Functions,
+            Command::Local => Command::Local,
+            Command::Return => Command::Return,
+            Command::Break => Command::Break,
+            Command::Continue => Command::Continue,
+            Command::External(program) => Command::External(program.clone()),
+            Command::Function(name) => Command::Function(name.clone()),
         }
     }
 
+    /// Every builtin command.
+    pub fn builtins() -> Vec<Command> {
+        vec![
+            Command::ChangeDir,
+            Command::GetPath,
+            Command::SetPath,
+            Command::History,
+            Command::LastCommand,
+            Command::NCommand,
+            Command::NMinusCommand,
+            Command::Alias,
+            Command::Unalias,
+            Command::Exit,
+            Command::Jobs,
+            Command::Foreground,
+            Command::Background,
+            Command::Wait,
+            Command::Kill,
+            Command::Export,
+            Command::Unset,
+            Command::Set,
+            Command::Env,
+            Command::PushDir,
+            Command::PopDir,
+            Command::Dirs,
+            Command::Functions,
+            Command::Local,
+            Command::Return,
+            Command::Break,
+            Command::Continue,
+        ]
+    }
+
+    /// Whether the command re-runs an entry from the history rather than doing anything
+    /// itself, in which case it is not recorded in the history.
+    pub fn is_history_invocation(&self) -> bool {
+        matches!(self, Command::LastCommand | Command::NCommand | Command::NMinusCommand)
+    }
+
+    #[allow(clippy::inherent_to_string)]
     pub fn to_string(&self) -> String {
         self.get_command().name
     }
@@ -184,176 +434,891 @@ struct UnAlias;
 
 struct Exit;
 
+struct Jobs;
+
+struct Foreground;
+
+struct Background;
+
+struct Wait;
+
+struct Kill;
+
+struct Export;
+
+struct Unset;
+
+struct Set;
+
+struct Env;
+
+struct PushDir;
+
+struct PopDir;
+
+struct Dirs;
+
+struct Functions;
+
+struct Local;
+
+struct Return;
+
+struct Break;
+
+struct Continue;
+
+struct External {
+    program: String,
+}
+
+struct CallFunction {
+    name: String,
+}
+
 pub(crate) trait CommandExecutor {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool;
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status;
+}
+
+// Resolves a directory given to cd or pushd against the working directory, following
+// `..` and symbolic links. `~` and `~user` name home directories and `-` the previous
+// working directory.
+fn resolve_directory(directory: &str, terminal: &Terminal) -> Result<String, String> {
+    let path: PathBuf = if directory == "-" {
+        PathBuf::from(terminal.variables.get("OLDPWD").ok_or("OLDPWD is not set")?)
+    } else if let Some(tilde) = directory.strip_prefix('~') {
+        let (user, rest) = tilde.split_once('/').unwrap_or((tilde, ""));
+        let home = if user.is_empty() {
+            terminal.variables.get("HOME").map(String::from).ok_or("HOME is not set")?
+        } else {
+            home_directory_of(user).ok_or(format!("No such user \"{}\"", user))?
+        };
+        Path::new(&home).join(rest)
+    } else {
+        Path::new(&terminal.working_dir).join(directory)
+    };
+    let path = path.canonicalize().map_err(|error| format!("{}: {}", directory, error))?;
+    if !path.is_dir() {
+        return Err(format!("{}: Not a directory", directory));
+    }
+    path.into_os_string().into_string().map_err(|_| format!("{}: Not a valid path", directory))
 }
 
-fn is_valid_directory(dir: &str) -> bool {
-    metadata(dir).map(|metadata| metadata.is_dir()).unwrap_or(false)
+fn home_directory_of(user: &str) -> Option<String> {
+    let name = CString::new(user).ok()?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return None;
+    }
+    let home = unsafe { CStr::from_ptr((*entry).pw_dir) };
+    home.to_str().ok().map(String::from)
+}
+
+fn change_directory(directory: &str, terminal: &mut Terminal) -> Result<(), String> {
+    let resolved = resolve_directory(directory, terminal)?;
+    terminal.set_working_dir(resolved).map_err(|error| format!("{}: {}", directory, error))
+}
+
+// The working directory followed by the directory stack from its top, home shown as ~
+fn directory_stack(terminal: &Terminal) -> Vec<String> {
+    let home = terminal.variables.get("HOME").unwrap_or_default();
+    let mut dirs: Vec<String> = vec![terminal.working_dir.clone()];
+    dirs.extend(terminal.dir_stack.iter().rev().cloned());
+    dirs.into_iter()
+        .map(|dir| match dir.strip_prefix(home) {
+            Some(rest) if !home.is_empty() && (rest.is_empty() || rest.starts_with('/')) => format!("~{}", rest),
+            _ => dir,
+        })
+        .collect()
 }
 
 impl CommandExecutor for ChangeDir {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        // TODO make directory handling relative to current path
-        if let Some(directory) = cmd_string_line {
-            if is_valid_directory(directory.as_str()) {
-                terminal.working_dir = directory;
-                return true;
-            } else {
-                println!("Directory not valid!")
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if args.len() > 1 {
+            errln!(streams, "Too many arguments, cd takes a single path.");
+            return Status::FAILURE;
+        }
+        // Without a path cd goes home
+        let directory = args.first().map(String::as_str).unwrap_or("~");
+        if let Err(error) = change_directory(directory, terminal) {
+            errln!(streams, "cd: {}", error);
+            return Status::FAILURE;
+        }
+        if directory == "-" {
+            outln!(streams, "{}", terminal.working_dir);
+        }
+        Status::SUCCESS
+    }
+}
+
+impl CommandExecutor for PushDir {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if args.len() > 1 {
+            errln!(streams, "Too many arguments, pushd takes a single path.");
+            return Status::FAILURE;
+        }
+        let previous = terminal.working_dir.clone();
+        let result = match args.first() {
+            Some(directory) => change_directory(directory, terminal),
+            // Without a path the top two directories are swapped
+            None => match terminal.dir_stack.pop() {
+                Some(top) => change_directory(top.as_str(), terminal).inspect_err(|_| terminal.dir_stack.push(top)),
+                None => Err(String::from("No other directory")),
+            },
+        };
+        if let Err(error) = result {
+            errln!(streams, "pushd: {}", error);
+            return Status::FAILURE;
+        }
+        terminal.dir_stack.push(previous);
+        outln!(streams, "{}", directory_stack(terminal).join(" "));
+        Status::SUCCESS
+    }
+}
+
+impl CommandExecutor for PopDir {
+    fn execute(&self, _args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        let top = match terminal.dir_stack.pop() {
+            Some(top) => top,
+            None => {
+                errln!(streams, "popd: Directory stack empty");
+                return Status::FAILURE;
             }
-        } else {
-            println!("Please input a path to change to.");
+        };
+        if let Err(error) = change_directory(top.as_str(), terminal) {
+            errln!(streams, "popd: {}", error);
+            terminal.dir_stack.push(top);
+            return Status::FAILURE;
         }
-        return false;
+        outln!(streams, "{}", directory_stack(terminal).join(" "));
+        Status::SUCCESS
+    }
+}
+
+impl CommandExecutor for Dirs {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        match args.first().map(String::as_str) {
+            None => outln!(streams, "{}", directory_stack(terminal).join(" ")),
+            Some("-c") => terminal.dir_stack.clear(),
+            Some("-v") => {
+                for (index, dir) in directory_stack(terminal).iter().enumerate() {
+                    outln!(streams, "{:>2}  {}", index, dir);
+                }
+            }
+            Some(_) => {
+                errln!(streams, "Print the directory stack with: > dirs [-c|-v]");
+                return Status::FAILURE;
+            }
+        }
+        Status::SUCCESS
     }
 }
 
 impl CommandExecutor for GetPath {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        println!("{}", terminal.system_path);
-        return true;
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        match args.first().map(String::as_str) {
+            None => outln!(streams, "{}", terminal.system_path()),
+            Some("-l") | Some("--lines") => {
+                for dir in terminal.system_path().split(':') {
+                    outln!(streams, "{}", dir);
+                }
+            }
+            Some(_) => {
+                errln!(streams, "Print the system path with: > getpath [-l|--lines]");
+                return Status::FAILURE;
+            }
+        }
+        Status::SUCCESS
+    }
+}
+
+// Warns about entries of the system path that are not directories, they are kept anyway
+// as they may be created later
+fn warn_missing_directories(dirs: &[&str], terminal: &Terminal, streams: &mut Streams) {
+    for dir in dirs {
+        if !dir.is_empty() && !Path::new(&terminal.working_dir).join(dir).is_dir() {
+            errln!(streams, "Warning: \"{}\" is not a directory", dir);
+        }
     }
 }
 
 impl CommandExecutor for SetPath {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        if let Some(directory) = cmd_string_line {
-            if is_valid_directory(directory.as_str()) {
-                terminal.system_path = directory;
-                return true;
-            } else {
-                println!("Directory not valid!")
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        let (option, dir) = match args.as_slice() {
+            [path] if !path.starts_with("--") => {
+                let dirs: Vec<&str> = path.split(':').collect();
+                warn_missing_directories(dirs.as_slice(), terminal, streams);
+                terminal.set_system_path(path.clone());
+                return Status::SUCCESS;
+            }
+            [option, dir] => (option.as_str(), dir.as_str()),
+            _ => {
+                errln!(streams, "Set the system path with: > setpath <dir>:<dir>... | --append|--prepend|--remove <dir>");
+                return Status::FAILURE;
+            }
+        };
+        // Adding a directory that is already there moves it
+        let mut dirs: Vec<&str> = terminal.system_path().split(':')
+            .filter(|existing| !existing.is_empty() && *existing != dir)
+            .collect();
+        let removed = dirs.len() != terminal.system_path().split(':').filter(|existing| !existing.is_empty()).count();
+        match option {
+            "--append" => dirs.push(dir),
+            "--prepend" => dirs.insert(0, dir),
+            "--remove" if removed => {}
+            "--remove" => {
+                errln!(streams, "\"{}\" is not on the system path.", dir);
+                return Status::FAILURE;
+            }
+            _ => {
+                errln!(streams, "Unknown option \"{}\", use --append, --prepend or --remove", option);
+                return Status::FAILURE;
             }
-        } else {
-            println!("Please a path to set.")
         }
-        return false;
+        if option != "--remove" {
+            warn_missing_directories(&[dir], terminal, streams);
+        }
+        let path = dirs.join(":");
+        terminal.set_system_path(path);
+        Status::SUCCESS
     }
 }
 
 impl CommandExecutor for History {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        let commands = terminal.get_history_commands();
-        if commands.is_empty() {
-            println!("There is no history!");
-        } else {
-            for (index, command) in commands.iter().enumerate() {
-                println!("{}. {}", index + 1, command.trim());
+    fn execute(&self, mut args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        let verbose = args.first().map(String::as_str) == Some("-v");
+        if verbose {
+            args.remove(0);
+        }
+        let entries = terminal.get_history_entries();
+        let mut listed: Vec<(usize, &HistoryEntry)> = entries.iter().enumerate().map(|(index, entry)| (index + 1, entry)).collect();
+        match args.iter().map(String::as_str).collect::<Vec<&str>>().as_slice() {
+            [] => {}
+            ["-c"] => {
+                terminal.clear_history();
+                return Status::SUCCESS;
+            }
+            ["-d", number] => {
+                let index = number.parse::<usize>().ok().and_then(|number| number.checked_sub(1));
+                return match index.and_then(|index| terminal.remove_history_entry(index)) {
+                    Some(_) => Status::SUCCESS,
+                    None => {
+                        errln!(streams, "history: {}: history position out of range", number);
+                        Status::FAILURE
+                    }
+                };
+            }
+            ["-a"] => return report_history_change(terminal.save_history(), "written", streams),
+            ["-r"] => return report_history_change(terminal.reload_history().map(|_| 0), "", streams),
+            ["-n"] => return report_history_change(terminal.merge_history(), "read", streams),
+            ["grep", pattern] => listed.retain(|(_, entry)| entry.line.contains(pattern)),
+            ["--dir", rest @ ..] if rest.len() <= 1 => {
+                let directory = match rest.first() {
+                    Some(directory) => match resolve_directory(directory, terminal) {
+                        Ok(directory) => directory,
+                        Err(error) => {
+                            errln!(streams, "history: {}", error);
+                            return Status::FAILURE;
+                        }
+                    },
+                    None => terminal.working_dir.clone(),
+                };
+                listed.retain(|(_, entry)| entry.details.as_ref().is_some_and(|details| details.working_dir == directory));
+            }
+            [count] => match count.parse::<usize>() {
+                Ok(count) => {
+                    listed.drain(..listed.len().saturating_sub(count));
+                }
+                Err(_) => {
+                    errln!(streams, "history: {}: numeric argument required", count);
+                    return Status::FAILURE;
+                }
+            },
+            _ => {
+                errln!(streams, "Usage: history [-v] [N | grep <pattern> | --dir [path]] | history -c | history -d <n> | history -a | -r | -n");
+                return Status::FAILURE;
+            }
+        }
+        if entries.is_empty() {
+            outln!(streams, "There is no history!");
+        }
+        for (number, entry) in listed {
+            if verbose {
+                outln!(streams, "{}. {}  {}", number, describe_run(entry), entry.line);
+            } else {
+                outln!(streams, "{}. {}", number, entry.line);
             }
         }
-        return true;
+        Status::SUCCESS
     }
 }
 
+// Reports how many entries `history -a` or `history -n` moved, or why it failed
+fn report_history_change(result: io::Result<usize>, moved: &str, streams: &mut Streams) -> Status {
+    match result {
+        Ok(0) => Status::SUCCESS,
+        Ok(count) => {
+            outln!(streams, "{} entries {}", count, moved);
+            Status::SUCCESS
+        }
+        Err(error) => {
+            errln!(streams, "history: {}", error);
+            Status::FAILURE
+        }
+    }
+}
+
+// When, where and how an entry ran, as listed by `history -v`
+fn describe_run(entry: &HistoryEntry) -> String {
+    match &entry.details {
+        Some(details) => format!("{}  {:>8}  {:>3}  {}", format_time(details.started),
+                                 format!("{:.3}s", details.duration.as_secs_f64()), details.status, details.working_dir),
+        None => format!("{:<19}  {:>8}  {:>3}  {}", "-", "-", "-", "-"),
+    }
+}
+
+// Seconds since the epoch as a local date and time
+fn format_time(seconds: i64) -> String {
+    let time = seconds as libc::time_t;
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    let mut buffer = [0u8; 32];
+    let format = c"%Y-%m-%d %H:%M:%S";
+    let written = unsafe {
+        if libc::localtime_r(&time, &mut local).is_null() {
+            return seconds.to_string();
+        }
+        libc::strftime(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len(), format.as_ptr(), &local)
+    };
+    String::from_utf8_lossy(&buffer[..written]).into_owned()
+}
+
 impl CommandExecutor for LastCommand {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        terminal.run_prev_command(1)
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        terminal.run_prev_command(-1, args.as_slice(), streams)
     }
 }
 
-fn nx_command_executor(cmd_string_line: Option<String>, terminal: &mut Terminal, go_back: bool) -> bool {
+fn nx_command_executor(args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams, go_back: bool) -> Status {
     let mut multiply: i32 = 1;
     if go_back {
         multiply = -1;
     }
-    return if let Some(number_str) = cmd_string_line {
-        return if let Ok(num) = number_str.parse::<i32>() {
-            terminal.run_prev_command(num * multiply)
+    if let Some(number_str) = args.first() {
+        if let Ok(num) = number_str.parse::<i32>() {
+            terminal.run_prev_command(num * multiply, &args[1..], streams)
         } else {
-            println!("Failed to parse number!");
-            false
+            errln!(streams, "Failed to parse number!");
+            Status::FAILURE
         }
     } else {
-        println!("Nothing to parse.");
-        false
+        errln!(streams, "Nothing to parse.");
+        Status::FAILURE
     }
 }
 
 
 impl CommandExecutor for NCommand {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        return nx_command_executor(cmd_string_line, terminal, false);
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        nx_command_executor(args, terminal, streams, false)
     }
 }
 
 impl CommandExecutor for NMinusCommand {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        return nx_command_executor(cmd_string_line, terminal, true);
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        nx_command_executor(args, terminal, streams, true)
     }
 }
 
 impl CommandExecutor for Alias {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        if cmd_string_line.is_none() {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if args.is_empty() {
             let aliases = terminal.get_aliases_string();
             if aliases.is_empty() {
-                println!("No aliases set!");
+                outln!(streams, "No aliases set!");
             } else {
-                for alias in terminal.get_aliases_string() {
-                    println!("{}", alias);
+                for alias in aliases {
+                    outln!(streams, "{}", alias);
                 }
             }
-            return true
-        } else {
-            let cmd_string_line = cmd_string_line.unwrap();
-            let alias_line: Vec<String> = cmd_string_line.splitn(3, " ").map(|v| String::from(v)).collect();
-            if alias_line.len() >= 2 {
-                let name = alias_line.get(0).unwrap();
-                let command = alias_line.get(1).unwrap();
-                let command_option = Command::get_command_enum(command.as_str());
-                if command_option.is_none() {
-                    println!("Unknown command \"{}\" trying to be set as an alias", command);
-                    return false;
-                } else if terminal.is_alias_present(name) {
-                    println!("This alias is already been set.");
+            return Status::SUCCESS;
+        }
+        let (name, text) = match args.as_slice() {
+            // `alias la=ls -a` the way bash writes it
+            [definition] if definition.contains('=') => {
+                let (name, text) = definition.split_once('=').unwrap();
+                (String::from(name), String::from(text))
+            }
+            [name] => {
+                return match terminal.get_alias(name) {
+                    Some(alias) => {
+                        outln!(streams, "{}", alias);
+                        Status::SUCCESS
+                    }
+                    None => {
+                        errln!(streams, "Set aliases with: > alias <name> <command>");
+                        Status::FAILURE
+                    }
+                };
+            }
+            // A single argument is the command text as it was quoted, e.g. 'ls | less'
+            [name, text] => (name.clone(), text.clone()),
+            // Separate arguments are quoted so they tokenize the same way when the alias is used
+            [name, words @ ..] => {
+                let words: Vec<String> = words.iter().map(|word| lexer::quote(word)).collect();
+                (name.clone(), words.join(" "))
+            }
+            [] => unreachable!(),
+        };
+        if text.trim().is_empty() {
+            errln!(streams, "Set aliases with: > alias <name> <command>");
+            return Status::FAILURE;
+        }
+        // The name has to come back as a single plain word when the line is tokenized
+        if lexer::quote(name.as_str()) != name || name.contains('=') {
+            errln!(streams, "\"{}\" is not a valid alias name", name);
+            return Status::FAILURE;
+        }
+        if terminal.is_alias_present(&name) {
+            errln!(streams, "This alias is already been set.");
+        }
+        terminal.add_alias(name, text);
+        Status::SUCCESS
+    }
+}
+
+impl CommandExecutor for UnAlias {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if let Some(name) = args.first() {
+            let aliases_removed: Vec<String> = terminal.remove_command_aliases(name);
+            if aliases_removed.is_empty() {
+                outln!(streams, "There are no aliases associated with that command.")
+            } else {
+                outln!(streams, "Removed the following aliases:");
+                for alias in &aliases_removed {
+                    outln!(streams, " - \"{}\"", alias)
                 }
+            }
+            Status::SUCCESS
+        } else {
+            errln!(streams, "Unset an alias with: > unalias <command> ");
+            Status::FAILURE
+        }
+    }
+}
 
-                if alias_line.len() == 2 { // no args
-                    terminal.add_alias(String::from(name), command_option.unwrap(), None);
-                } else { // Args
-                    let arguments = alias_line.get(2).unwrap();
-                    terminal.add_alias(String::from(name), command_option.unwrap(), Some(String::from(arguments)))
+impl CommandExecutor for Exit {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        // Without a status the shell exits with the status of the last command
+        let status = match args.as_slice() {
+            [] => terminal.last_status,
+            [code] => match code.parse::<i32>() {
+                Ok(code) => Status::exited(code & 0xff),
+                Err(_) => {
+                    errln!(streams, "exit: {}: numeric argument required", code);
+                    return Status::SYNTAX_ERROR;
                 }
-                return true;
-            } else {
-                println!("Set aliases with: > alias <name> <command>")
+            },
+            _ => {
+                errln!(streams, "Exit with: > exit [<status>]");
+                return Status::FAILURE;
             }
+        };
+        // In a subshell only the subshell ends
+        if terminal.is_subshell() {
+            terminal.exit_subshell(status);
+            return status;
         }
-        return false;
+        outln!(streams, "Goodbye!");
+        process::exit(status.code());
     }
 }
 
-impl CommandExecutor for UnAlias {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        return if cmd_string_line.is_none() {
-            println!("Unset an alias with: > unalias <command> ");
-            false
-        } else {
-            let cmd_string_line = cmd_string_line.unwrap();
-            let command_option: Option<(Command, String)> = terminal.parse_user_input(cmd_string_line);
-            return if let Some((command, _)) = command_option {
-                let aliases_removed: Vec<String> = terminal.remove_command_aliases(command);
-                if aliases_removed.is_empty() {
-                    println!("There are no aliases associated with that command.")
-                } else {
-                    println!("Removed the following aliases:");
-                    for alias in &aliases_removed {
-                        println!(" - \"{}\"", alias)
+impl CommandExecutor for Jobs {
+    fn execute(&self, _args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        for job in terminal.jobs.reap() {
+            outln!(streams, "[{}]   {:<22}{}", job.id, jobs::describe_status(job.last_status()), job.command_line);
+        }
+        let current = terminal.jobs.current_id();
+        for job in terminal.jobs.jobs() {
+            let marker = if Some(job.id) == current { "+" } else { " " };
+            let state = match job.state {
+                JobState::Running => "Running",
+                JobState::Stopped => "Stopped",
+            };
+            outln!(streams, "[{}]{}  {:<22}{}", job.id, marker, state, job.command_line);
+        }
+        Status::SUCCESS
+    }
+}
+
+// Finds the job named by the only argument, or the current job when there is none
+fn job_from_args(args: &[String], terminal: &Terminal, streams: &mut Streams) -> Option<usize> {
+    let id = match args.first() {
+        Some(spec) => terminal.jobs.parse_job_spec(spec),
+        None => terminal.jobs.current_id(),
+    };
+    if id.is_none() {
+        errln!(streams, "No such job: {}", args.first().map(String::as_str).unwrap_or("current"));
+    }
+    id
+}
+
+impl CommandExecutor for Foreground {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        let id = match job_from_args(args.as_slice(), terminal, streams) {
+            Some(id) => id,
+            None => return Status::FAILURE,
+        };
+        let mut job = terminal.jobs.remove(id).unwrap();
+        outln!(streams, "{}", job.command_line);
+        if job.state == JobState::Stopped {
+            if let Err(error) = job.signal(libc::SIGCONT) {
+                errln!(streams, "Failed to continue job {}: {}", id, error);
+            }
+            job.state = JobState::Running;
+        }
+        match terminal.jobs.wait_foreground(job) {
+            WaitOutcome::Exited(status) => status.map(|status| Status::from_exit_status(&status)).unwrap_or(Status::SUCCESS),
+            WaitOutcome::Stopped(id) => {
+                errln!(streams, "");
+                errln!(streams, "[{}]+  Stopped", id);
+                Status::signaled(libc::SIGTSTP)
+            }
+        }
+    }
+}
+
+impl CommandExecutor for Background {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        let id = match job_from_args(args.as_slice(), terminal, streams) {
+            Some(id) => id,
+            None => return Status::FAILURE,
+        };
+        let job = terminal.jobs.get_mut(id).unwrap();
+        if job.state == JobState::Running {
+            errln!(streams, "Job {} is already running in the background.", id);
+            return Status::SUCCESS;
+        }
+        if let Err(error) = job.signal(libc::SIGCONT) {
+            errln!(streams, "Failed to continue job {}: {}", id, error);
+            return Status::FAILURE;
+        }
+        job.state = JobState::Running;
+        outln!(streams, "[{}]+ {} &", id, job.command_line);
+        Status::SUCCESS
+    }
+}
+
+impl CommandExecutor for Wait {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if args.is_empty() {
+            let ids: Vec<usize> = terminal.jobs.jobs().iter()
+                .filter(|job| job.state == JobState::Running)
+                .map(|job| job.id)
+                .collect();
+            for id in ids {
+                // Ctrl-C gives up waiting the way it would have ended a foreground job
+                if terminal.jobs.wait_background(id).is_err() {
+                    return Status::signaled(libc::SIGINT);
+                }
+            }
+            return Status::SUCCESS;
+        }
+        let mut status = Status::SUCCESS;
+        for spec in &args {
+            match terminal.jobs.parse_job_spec(spec) {
+                Some(id) => match terminal.jobs.wait_background(id) {
+                    Ok(Some(job)) => {
+                        status = job.last_status().map(|exit_status| Status::from_exit_status(&exit_status)).unwrap_or(Status::SUCCESS);
                     }
+                    Ok(None) => {}
+                    Err(_) => return Status::signaled(libc::SIGINT),
+                },
+                None => {
+                    errln!(streams, "No such job: {}", spec);
+                    status = Status::FAILURE;
+                }
+            }
+        }
+        status
+    }
+}
+
+impl CommandExecutor for Kill {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        let mut signal = libc::SIGTERM;
+        let mut targets: &[String] = args.as_slice();
+        if let Some(name) = args.first().and_then(|arg| arg.strip_prefix('-')) {
+            match jobs::parse_signal(name) {
+                Some(parsed) => signal = parsed,
+                None => {
+                    errln!(streams, "Unknown signal \"{}\"", name);
+                    return Status::FAILURE;
+                }
+            }
+            targets = &args[1..];
+        }
+        if targets.is_empty() {
+            errln!(streams, "Send a signal with: > kill [-<signal>] %<job> | <pid>");
+            return Status::FAILURE;
+        }
+
+        let mut status = Status::SUCCESS;
+        for target in targets {
+            let result = if target.starts_with('%') {
+                match terminal.jobs.parse_job_spec(target).and_then(|id| terminal.jobs.get_mut(id)) {
+                    // A stopped job has to be continued to act on anything but SIGCONT
+                    Some(job) => job.signal(signal).and_then(|_| match job.state {
+                        JobState::Stopped if signal != libc::SIGSTOP => job.signal(libc::SIGCONT),
+                        _ => Ok(()),
+                    }),
+                    None => Err(io::Error::new(io::ErrorKind::NotFound, "no such job")),
                 }
-                true
             } else {
-                println!("That is not a valid command!");
-                false
+                match target.parse::<i32>() {
+                    Ok(pid) if unsafe { libc::kill(pid, signal) } == 0 => Ok(()),
+                    Ok(_) => Err(io::Error::last_os_error()),
+                    Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "not a job or process id")),
+                }
             };
+            if let Err(error) = result {
+                errln!(streams, "kill {}: {}", target, error);
+                status = Status::FAILURE;
+            }
+        }
+        status
+    }
+}
+
+impl CommandExecutor for Export {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if args.is_empty() {
+            for (name, value) in terminal.variables.exported() {
+                outln!(streams, "export {}={}", name, lexer::quote(value));
+            }
+            return Status::SUCCESS;
+        }
+        let mut status = Status::SUCCESS;
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(String::from(value))),
+                None => (arg.as_str(), None),
+            };
+            if is_valid_name(name) {
+                terminal.variables.export(name, value);
+            } else {
+                errln!(streams, "export: \"{}\" is not a valid variable name", name);
+                status = Status::FAILURE;
+            }
+        }
+        status
+    }
+}
+
+impl CommandExecutor for Unset {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        // `unset -f` removes functions rather than variables
+        let functions = args.first().map(String::as_str) == Some("-f");
+        let names = if functions { &args[1..] } else { &args[..] };
+        if names.is_empty() {
+            errln!(streams, "Remove variables with: > unset <name> ... or functions with: > unset -f <name> ...");
+            return Status::FAILURE;
+        }
+        for name in names {
+            if functions {
+                terminal.remove_function(name);
+            } else {
+                terminal.variables.unset(name.as_str());
+            }
+        }
+        Status::SUCCESS
+    }
+}
+
+impl CommandExecutor for Set {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        let on = match args.first().map(String::as_str) {
+            None => {
+                for (name, variable) in terminal.variables.all() {
+                    outln!(streams, "{}={}", name, lexer::quote(variable.value.as_str()));
+                }
+                return Status::SUCCESS;
+            }
+            Some("-o") => true,
+            Some("+o") => false,
+            Some(_) => {
+                errln!(streams, "Change an option with: > set -o|+o <option>");
+                return Status::FAILURE;
+            }
         };
+        if args.len() == 1 {
+            for option in ShellOption::ALL {
+                let state = if terminal.options.is_set(option) { "on" } else { "off" };
+                outln!(streams, "{:<15}{}", option.name(), state);
+            }
+            return Status::SUCCESS;
+        }
+        let mut status = Status::SUCCESS;
+        for name in &args[1..] {
+            match ShellOption::from_name(name) {
+                Some(ShellOption::SaveFunctions) => terminal.set_save_functions(on),
+                Some(option) => terminal.options.set(option, on),
+                None => {
+                    errln!(streams, "Unknown option \"{}\"", name);
+                    status = Status::FAILURE;
+                }
+            }
+        }
+        status
     }
 }
 
-impl CommandExecutor for Exit {
-    fn execute(&self, cmd_string_line: Option<String>, terminal: &mut Terminal) -> bool {
-        println!("Goodbye!");
-        process::exit(0);
+impl CommandExecutor for Env {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if !args.is_empty() {
+            errln!(streams, "env takes no arguments, run a command with: > <name>=<value> <command>");
+            return Status::FAILURE;
+        }
+        for (name, value) in terminal.variables.exported() {
+            outln!(streams, "{}={}", name, value);
+        }
+        Status::SUCCESS
+    }
+}
+
+impl CommandExecutor for Functions {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if args.first().map(String::as_str) == Some("-d") {
+            if args.len() == 1 {
+                errln!(streams, "Remove functions with: > functions -d <name> ...");
+                return Status::FAILURE;
+            }
+            let mut status = Status::SUCCESS;
+            for name in &args[1..] {
+                if !terminal.remove_function(name) {
+                    errln!(streams, "No function named \"{}\"", name);
+                    status = Status::FAILURE;
+                }
+            }
+            return status;
+        }
+        let names = if args.is_empty() { terminal.get_function_names() } else { args };
+        let mut status = Status::SUCCESS;
+        for name in names {
+            match terminal.get_function(name.as_str()) {
+                Some(text) => outln!(streams, "{}", text),
+                None => {
+                    errln!(streams, "No function named \"{}\"", name);
+                    status = Status::FAILURE;
+                }
+            }
+        }
+        status
+    }
+}
+
+impl CommandExecutor for Local {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        if args.is_empty() {
+            errln!(streams, "Make variables local with: > local <name>[=<value>] ...");
+            return Status::FAILURE;
+        }
+        let mut status = Status::SUCCESS;
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(String::from(value))),
+                None => (arg.as_str(), None),
+            };
+            if !is_valid_name(name) {
+                errln!(streams, "Invalid variable name \"{}\"", name);
+                status = Status::FAILURE;
+                continue;
+            }
+            if !terminal.declare_local(name, value) {
+                errln!(streams, "local can only be used in a function");
+                return Status::FAILURE;
+            }
+        }
+        status
+    }
+}
+
+impl CommandExecutor for Return {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        // Without a status the function returns the status of the last command
+        let status = match args.as_slice() {
+            [] => terminal.last_status,
+            [code] => match code.parse::<i32>() {
+                Ok(code) => Status::exited(code & 0xff),
+                Err(_) => {
+                    errln!(streams, "return: {}: numeric argument required", code);
+                    Status::SYNTAX_ERROR
+                }
+            },
+            _ => {
+                errln!(streams, "End a function with: > return [<status>]");
+                return Status::FAILURE;
+            }
+        };
+        if !terminal.return_from_function(status) {
+            errln!(streams, "return can only be used in a function");
+            return Status::FAILURE;
+        }
+        status
+    }
+}
+
+impl CommandExecutor for Break {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        loop_control_executor(args, terminal, streams, false)
+    }
+}
+
+impl CommandExecutor for Continue {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        loop_control_executor(args, terminal, streams, true)
+    }
+}
+
+// Leaves the given number of loops, one by default, going on with the next iteration of
+// the last one left when continuing
+fn loop_control_executor(args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams, continuing: bool) -> Status {
+    let name = if continuing { "continue" } else { "break" };
+    let levels = match args.as_slice() {
+        [] => 1,
+        [levels] => match levels.parse::<usize>() {
+            Ok(levels) if levels > 0 => levels,
+            _ => {
+                errln!(streams, "{}: {}: loop count out of range", name, levels);
+                return Status::FAILURE;
+            }
+        },
+        _ => {
+            errln!(streams, "Leave loops with: > {} [<number of loops>]", name);
+            return Status::FAILURE;
+        }
+    };
+    let control = if continuing { LoopControl::Continue(levels) } else { LoopControl::Break(levels) };
+    if !terminal.leave_loops(control) {
+        errln!(streams, "{} can only be used in a loop", name);
+        return Status::FAILURE;
+    }
+    Status::SUCCESS
+}
+
+impl CommandExecutor for CallFunction {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        terminal.call_function(self.name.as_str(), args, streams)
+    }
+}
+
+impl CommandExecutor for External {
+    fn execute(&self, args: Vec<String>, terminal: &mut Terminal, streams: &mut Streams) -> Status {
+        let mut words: Vec<String> = vec![self.program.clone()];
+        words.extend(args);
+        let command_line = words.join(" ");
+        let stage = Stage { assignments: Vec::new(), words, redirects: Vec::new(), compound: None };
+        pipeline::run(vec![(Some(Command::External(self.program.clone())), stage)], false,
+                             command_line.as_str(), terminal, streams)
     }
 }
@@ -0,0 +1,187 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::command::Command;
+use crate::terminal::{is_executable, Terminal};
+
+/// A possible completion of the word being typed.
+pub(crate) struct Candidate {
+    // What the word is replaced with
+    pub replacement: String,
+    // How the candidate is listed
+    pub display: String,
+    // Whether a space is added after the word once it is the only candidate
+    pub finished: bool,
+}
+
+/// The candidates for the word that ends at the cursor, along with where the word starts.
+pub(crate) struct Completion {
+    pub start: usize,
+    pub candidates: Vec<Candidate>,
+}
+
+/// Completes the word before the cursor. The command of a line is completed from the
+/// builtins, aliases, functions and programs on the system path, `!` from the numbers
+/// of history entries and anything else as a path relative to the working directory,
+/// or to the home directory after a leading `~/`.
+pub(crate) fn complete(before_cursor: &[char], terminal: &Terminal) -> Completion {
+    let start = before_cursor.iter()
+        .rposition(|c| c.is_whitespace() || is_separator(*c))
+        .map(|position| position + 1)
+        .unwrap_or(0);
+    let word: String = before_cursor[start..].iter().collect();
+    let command_position = before_cursor[..start].iter().rev()
+        .find(|c| !c.is_whitespace())
+        .map(|c| is_separator(*c))
+        .unwrap_or(true);
+
+    let candidates = if let Some(number) = word.strip_prefix('!') {
+        complete_history(number, terminal)
+    } else if command_position && !word.contains('/') {
+        complete_command(unquote(word.as_str()).as_str(), terminal)
+    } else {
+        complete_path(unquote(word.as_str()).as_str(), terminal, command_position)
+    };
+    Completion { start, candidates }
+}
+
+/// The longest prefix shared by every candidate.
+pub(crate) fn common_prefix(candidates: &[Candidate]) -> String {
+    let mut prefix: Vec<char> = match candidates.first() {
+        Some(candidate) => candidate.replacement.chars().collect(),
+        None => return String::new(),
+    };
+    for candidate in &candidates[1..] {
+        let shared = prefix.iter().zip(candidate.replacement.chars())
+            .take_while(|(a, b)| **a == *b)
+            .count();
+        prefix.truncate(shared);
+    }
+    prefix.into_iter().collect()
+}
+
+/// Lays out candidates in columns filling the width, going down each column first the
+/// way bash lists them.
+pub(crate) fn format_columns(candidates: &[Candidate], width: usize) -> String {
+    let column_width = candidates.iter().map(|candidate| candidate.display.chars().count()).max().unwrap_or(0) + 2;
+    let columns = (width / column_width).max(1);
+    let rows = candidates.len().div_ceil(columns);
+    let mut output = String::new();
+    for row in 0..rows {
+        let mut line = String::new();
+        for column in 0..columns {
+            if let Some(candidate) = candidates.get(column * rows + row) {
+                line.push_str(format!("{:<width$}", candidate.display, width = column_width).as_str());
+            }
+        }
+        output.push_str(line.trim_end());
+        output.push_str("\r\n");
+    }
+    output
+}
+
+// Operators after which a new command starts
+fn is_separator(c: char) -> bool {
+    matches!(c, '|' | '&' | ';' | '(')
+}
+
+// The text of a partly typed word, with its quotes and escapes removed
+fn unquote(word: &str) -> String {
+    let mut text = String::new();
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            '\'' | '"' => {}
+            _ => text.push(c),
+        }
+    }
+    text
+}
+
+// Escapes the characters the lexer would otherwise treat specially
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if c.is_whitespace() || matches!(c, '\'' | '"' | '\\' | '|' | '&' | ';' | '<' | '>' | '(' | ')' | '$' | '*' | '?' | '[' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn complete_history(number: &str, terminal: &Terminal) -> Vec<Candidate> {
+    terminal.get_history_commands().iter().enumerate()
+        .map(|(index, command)| (index + 1, command))
+        .filter(|(index, _)| index.to_string().starts_with(number))
+        .map(|(index, command)| Candidate {
+            replacement: format!("!{}", index),
+            display: format!("{:>4}  {}", index, command.trim()),
+            finished: true,
+        })
+        .collect()
+}
+
+fn complete_command(prefix: &str, terminal: &Terminal) -> Vec<Candidate> {
+    let mut names: Vec<String> = Command::builtins().iter()
+        .map(|command| command.to_string())
+        // History invocations are prefixes, not words of their own
+        .filter(|name| !name.starts_with('!'))
+        .collect();
+    names.extend(terminal.get_alias_names());
+    names.extend(terminal.get_function_names());
+    for dir in terminal.system_path().split(':').filter(|dir| !dir.is_empty()) {
+        let dir = Path::new(&terminal.working_dir).join(dir);
+        for (name, path) in list_dir(dir.as_path()) {
+            if name.starts_with(prefix) && is_executable(path.as_path()) {
+                names.push(name);
+            }
+        }
+    }
+    names.retain(|name| name.starts_with(prefix));
+    names.sort();
+    names.dedup();
+    names.into_iter()
+        .map(|name| Candidate { replacement: escape(name.as_str()), display: name, finished: true })
+        .collect()
+}
+
+// Completes a path, only offering executables and directories for a command
+fn complete_path(word: &str, terminal: &Terminal, command_position: bool) -> Vec<Candidate> {
+    let (dir_part, prefix) = match word.rfind('/') {
+        Some(slash) => (&word[..slash + 1], &word[slash + 1..]),
+        None => ("", word),
+    };
+    let dir: PathBuf = match dir_part.strip_prefix("~/") {
+        Some(rest) => Path::new(terminal.variables.get("HOME").unwrap_or_default()).join(rest),
+        None => Path::new(&terminal.working_dir).join(dir_part),
+    };
+    list_dir(dir.as_path()).into_iter()
+        .filter(|(name, _)| name.starts_with(prefix) && (prefix.starts_with('.') || !name.starts_with('.')))
+        .filter_map(|(name, path)| {
+            let is_dir = path.is_dir();
+            if command_position && !is_dir && !is_executable(path.as_path()) {
+                return None;
+            }
+            let display = if is_dir { format!("{}/", name) } else { name };
+            Some(Candidate {
+                replacement: escape(format!("{}{}", dir_part, display).as_str()),
+                display,
+                finished: !is_dir,
+            })
+        })
+        .collect()
+}
+
+// The entries of a directory sorted by name, nothing if it can't be read
+fn list_dir(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut entries: Vec<(String, PathBuf)> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok().map(|name| (name, entry.path())))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    entries.sort();
+    entries
+}
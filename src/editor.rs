@@ -0,0 +1,429 @@
+use std::io;
+use std::io::{IsTerminal, Write};
+
+use crate::completion;
+use crate::terminal::Terminal;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_B: u8 = 0x02;
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_E: u8 = 0x05;
+const CTRL_F: u8 = 0x06;
+const CTRL_G: u8 = 0x07;
+const CTRL_H: u8 = 0x08;
+const TAB: u8 = 0x09;
+const CTRL_K: u8 = 0x0b;
+const CTRL_L: u8 = 0x0c;
+const CTRL_N: u8 = 0x0e;
+const CTRL_P: u8 = 0x10;
+const CTRL_R: u8 = 0x12;
+const CTRL_T: u8 = 0x14;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const CTRL_Y: u8 = 0x19;
+const ESCAPE: u8 = 0x1b;
+const BACKSPACE: u8 = 0x7f;
+
+/// A key press, with escape sequences already decoded.
+#[derive(Clone, Copy, PartialEq)]
+enum Key {
+    Char(char),
+    Control(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+    WordLeft,
+    WordRight,
+    // An escape sequence that is not bound to anything
+    Unknown,
+}
+
+/// Puts the terminal in raw mode until dropped, when its previous settings are restored.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        // Keys arrive one at a time, unechoed, and Ctrl-C or Ctrl-Z are plain keys
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.original);
+        }
+    }
+}
+
+/// Reads lines of input with emacs style editing and history navigation when stdin is
+/// a terminal, and plain lines otherwise.
+pub(crate) struct LineEditor {
+    // The text last removed with Ctrl-K, Ctrl-U or Ctrl-W, put back with Ctrl-Y
+    kill_buffer: Vec<char>,
+}
+
+// The line being edited
+struct Line {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl Line {
+    fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    fn insert(&mut self, chars: &[char]) {
+        for (offset, c) in chars.iter().enumerate() {
+            self.chars.insert(self.cursor + offset, *c);
+        }
+        self.cursor += chars.len();
+    }
+
+    // Start of the word before the cursor, skipping whitespace first
+    fn word_start(&self) -> usize {
+        let mut index = self.cursor;
+        while index > 0 && self.chars[index - 1].is_whitespace() {
+            index -= 1;
+        }
+        while index > 0 && !self.chars[index - 1].is_whitespace() {
+            index -= 1;
+        }
+        index
+    }
+
+    fn word_end(&self) -> usize {
+        let mut index = self.cursor;
+        while index < self.chars.len() && self.chars[index].is_whitespace() {
+            index += 1;
+        }
+        while index < self.chars.len() && !self.chars[index].is_whitespace() {
+            index += 1;
+        }
+        index
+    }
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor { kill_buffer: Vec::new() }
+    }
+
+    /// Shows the prompt and reads a line, returning None at the end of input. Up and
+    /// Down walk through the history of the terminal, Ctrl-R searches it and Tab
+    /// completes the word before the cursor.
+    pub fn read_line(&mut self, prompt: &str, terminal: &Terminal) -> io::Result<Option<String>> {
+        if !io::stdin().is_terminal() {
+            return read_plain_line(prompt);
+        }
+        let raw_mode = RawMode::enable()?;
+        let line = self.edit(prompt, terminal);
+        drop(raw_mode);
+        line
+    }
+
+    fn edit(&mut self, prompt: &str, terminal: &Terminal) -> io::Result<Option<String>> {
+        let history = terminal.get_history_commands();
+        let history = history.as_slice();
+        let mut line = Line { chars: Vec::new(), cursor: 0 };
+        // Position in the history while walking it, the history length being the new line
+        let mut history_index = history.len();
+        // The new line, kept while walking the history
+        let mut pending = String::new();
+        refresh(prompt, &line)?;
+
+        loop {
+            let mut key = read_key()?;
+            if key == Some(Key::Control(CTRL_R)) {
+                // The key that ended the search is handled as usual
+                let (found, next_key) = reverse_search(prompt, history, &line)?;
+                line.set(found.as_str());
+                key = next_key;
+            }
+            match key {
+                None if line.chars.is_empty() => {
+                    write_out("\r\n")?;
+                    return Ok(None);
+                }
+                None | Some(Key::Char('\r')) | Some(Key::Char('\n')) => {
+                    line.cursor = line.chars.len();
+                    refresh(prompt, &line)?;
+                    write_out("\r\n")?;
+                    return Ok(Some(line.text()));
+                }
+                Some(Key::Control(CTRL_D)) if line.chars.is_empty() => {
+                    write_out("\r\n")?;
+                    return Ok(None);
+                }
+                Some(Key::Control(CTRL_C)) => {
+                    write_out("^C\r\n")?;
+                    line.set("");
+                    history_index = history.len();
+                }
+                Some(Key::Control(CTRL_A)) | Some(Key::Home) => line.cursor = 0,
+                Some(Key::Control(CTRL_E)) | Some(Key::End) => line.cursor = line.chars.len(),
+                Some(Key::Control(CTRL_B)) | Some(Key::Left) => line.cursor = line.cursor.saturating_sub(1),
+                Some(Key::Control(CTRL_F)) | Some(Key::Right) => line.cursor = (line.cursor + 1).min(line.chars.len()),
+                Some(Key::WordLeft) => line.cursor = line.word_start(),
+                Some(Key::WordRight) => line.cursor = line.word_end(),
+                Some(Key::Control(BACKSPACE)) | Some(Key::Control(CTRL_H)) => {
+                    if line.cursor > 0 {
+                        line.cursor -= 1;
+                        line.chars.remove(line.cursor);
+                    }
+                }
+                Some(Key::Control(CTRL_D)) | Some(Key::Delete) => {
+                    if line.cursor < line.chars.len() {
+                        line.chars.remove(line.cursor);
+                    }
+                }
+                Some(Key::Control(CTRL_K)) => self.kill_buffer = line.chars.split_off(line.cursor),
+                Some(Key::Control(CTRL_U)) => {
+                    self.kill_buffer = line.chars.drain(..line.cursor).collect();
+                    line.cursor = 0;
+                }
+                Some(Key::Control(CTRL_W)) => {
+                    let start = line.word_start();
+                    self.kill_buffer = line.chars.drain(start..line.cursor).collect();
+                    line.cursor = start;
+                }
+                Some(Key::Control(CTRL_Y)) => line.insert(self.kill_buffer.clone().as_slice()),
+                Some(Key::Control(CTRL_T)) => {
+                    if line.cursor > 0 && line.chars.len() > 1 {
+                        let position = line.cursor.min(line.chars.len() - 1);
+                        line.chars.swap(position - 1, position);
+                        line.cursor = position + 1;
+                    }
+                }
+                Some(Key::Control(CTRL_L)) => write_out("\x1b[H\x1b[2J")?,
+                Some(Key::Control(CTRL_P)) | Some(Key::Up) => {
+                    if history_index > 0 {
+                        if history_index == history.len() {
+                            pending = line.text();
+                        }
+                        history_index -= 1;
+                        line.set(history[history_index].as_str());
+                    }
+                }
+                Some(Key::Control(CTRL_N)) | Some(Key::Down) => {
+                    if history_index < history.len() {
+                        history_index += 1;
+                        match history.get(history_index) {
+                            Some(entry) => line.set(entry.as_str()),
+                            None => line.set(pending.as_str()),
+                        }
+                    }
+                }
+                Some(Key::Control(TAB)) => complete(&mut line, terminal)?,
+                Some(Key::Char(c)) => line.insert(&[c]),
+                Some(_) => {}
+            }
+            refresh(prompt, &line)?;
+        }
+    }
+}
+
+/// Completes the word before the cursor when there is a single candidate, or as far as
+/// the candidates agree. When that does not add anything they are listed instead.
+fn complete(line: &mut Line, terminal: &Terminal) -> io::Result<()> {
+    let completion = completion::complete(&line.chars[..line.cursor], terminal);
+    let typed = line.cursor - completion.start;
+    let replacement = match completion.candidates.as_slice() {
+        [] => return write_out("\x07"),
+        [candidate] if candidate.finished => format!("{} ", candidate.replacement),
+        [candidate] => candidate.replacement.clone(),
+        candidates => completion::common_prefix(candidates),
+    };
+    if completion.candidates.len() > 1 && replacement.chars().count() <= typed {
+        write_out("\r\n")?;
+        return write_out(completion::format_columns(completion.candidates.as_slice(), terminal_width()).as_str());
+    }
+    line.chars.drain(completion.start..line.cursor);
+    line.cursor = completion.start;
+    let replacement: Vec<char> = replacement.chars().collect();
+    line.insert(replacement.as_slice());
+    Ok(())
+}
+
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == -1 || size.ws_col == 0 {
+        return 80;
+    }
+    size.ws_col as usize
+}
+
+// Without a terminal there is nothing to edit, the prompt is still shown
+fn read_plain_line(prompt: &str) -> io::Result<Option<String>> {
+    write_out(prompt)?;
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        println!();
+        return Ok(None);
+    }
+    let length = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(length);
+    Ok(Some(line))
+}
+
+/// Searches the history backwards for entries containing what is typed, until a key
+/// other than a printable character, Backspace or Ctrl-R is pressed. Returns the entry
+/// found along with that key, Ctrl-G and Ctrl-C giving back the line as it was.
+fn reverse_search(prompt: &str, history: &[String], line: &Line) -> io::Result<(String, Option<Key>)> {
+    let mut query = String::new();
+    let mut index = history.len();
+    let mut found = line.text();
+    loop {
+        write_out(format!("\r(reverse-i-search)'{}': {}\x1b[K", query, found).as_str())?;
+        let key = read_key()?;
+        let mut search_from = index;
+        match key {
+            Some(Key::Control(CTRL_R)) => {}
+            Some(Key::Char(c)) if c != '\r' && c != '\n' => {
+                query.push(c);
+                search_from = (index + 1).min(history.len());
+            }
+            Some(Key::Control(BACKSPACE)) | Some(Key::Control(CTRL_H)) => {
+                query.pop();
+                search_from = history.len();
+            }
+            Some(Key::Control(CTRL_G)) | Some(Key::Control(CTRL_C)) => {
+                write_out(format!("\r{}\x1b[K", prompt).as_str())?;
+                return Ok((line.text(), Some(Key::Unknown)));
+            }
+            key => return Ok((found, key)),
+        }
+        if query.is_empty() {
+            continue;
+        }
+        if let Some(position) = history[..search_from].iter().rposition(|entry| entry.contains(query.as_str())) {
+            index = position;
+            found = history[position].clone();
+        }
+    }
+}
+
+// Redraws the prompt and the line, leaving the cursor where it is in the line
+fn refresh(prompt: &str, line: &Line) -> io::Result<()> {
+    let column = prompt.chars().count() + line.cursor;
+    let mut output = format!("\r{}{}\x1b[K\r", prompt, line.text());
+    if column > 0 {
+        output.push_str(format!("\x1b[{}C", column).as_str());
+    }
+    write_out(output.as_str())
+}
+
+fn write_out(text: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    stdout.write_all(text.as_bytes())?;
+    stdout.flush()
+}
+
+// Reads straight from the file descriptor, as buffering stdin would keep back keys
+// typed ahead for the programs the shell runs
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte: u8 = 0;
+    loop {
+        let result = unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        if result == 1 {
+            return Ok(Some(byte));
+        }
+        if result == 0 {
+            return Ok(None);
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+// Reads a key, None meaning the end of input
+fn read_key() -> io::Result<Option<Key>> {
+    let byte = match read_byte()? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+    Ok(Some(match byte {
+        ESCAPE => read_escape()?,
+        b'\r' | b'\n' => Key::Char(byte as char),
+        0..=0x1f | BACKSPACE => Key::Control(byte),
+        0x20..=0x7e => Key::Char(byte as char),
+        _ => read_utf8(byte)?,
+    }))
+}
+
+fn read_escape() -> io::Result<Key> {
+    Ok(match read_byte()? {
+        Some(b'b') => Key::WordLeft,
+        Some(b'f') => Key::WordRight,
+        Some(b'[') | Some(b'O') => {
+            let mut sequence: Vec<u8> = Vec::new();
+            // Parameters are digits and semicolons, the final byte is a letter or `~`
+            while let Some(byte) = read_byte()? {
+                sequence.push(byte);
+                if !byte.is_ascii_digit() && byte != b';' {
+                    break;
+                }
+            }
+            match sequence.as_slice() {
+                b"A" => Key::Up,
+                b"B" => Key::Down,
+                b"C" => Key::Right,
+                b"D" => Key::Left,
+                b"H" | b"1~" | b"7~" => Key::Home,
+                b"F" | b"4~" | b"8~" => Key::End,
+                b"3~" => Key::Delete,
+                b"1;5C" | b"1;3C" => Key::WordRight,
+                b"1;5D" | b"1;3D" => Key::WordLeft,
+                _ => Key::Unknown,
+            }
+        }
+        _ => Key::Unknown,
+    })
+}
+
+fn read_utf8(first: u8) -> io::Result<Key> {
+    let length = match first {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Ok(Key::Unknown),
+    };
+    let mut bytes: Vec<u8> = vec![first];
+    for _ in 1..length {
+        match read_byte()? {
+            Some(byte) => bytes.push(byte),
+            None => break,
+        }
+    }
+    Ok(match std::str::from_utf8(bytes.as_slice()).ok().and_then(|text| text.chars().next()) {
+        Some(c) => Key::Char(c),
+        None => Key::Unknown,
+    })
+}
@@ -0,0 +1,467 @@
+use std::process;
+
+use crate::glob;
+use crate::lexer;
+use crate::lexer::{Quoting, Token, Word};
+use crate::options::ShellOption;
+use crate::pipeline::{Redirect, Stage};
+use crate::terminal::Terminal;
+use crate::variables::is_valid_name;
+
+// What `$0` expands to
+const SHELL_NAME: &str = "rust-terminal";
+
+// A field being built, along with the pattern it is matched against the filesystem
+// with, in which anything quoted is escaped
+#[derive(Default)]
+struct Field {
+    text: String,
+    pattern: String,
+    // Whether the pattern has an unquoted `*`, `?` or `[`
+    glob: bool,
+}
+
+// The fields a word expands to, built up one part at a time
+struct Fields {
+    fields: Vec<Field>,
+    // None until something, even an empty quoted string, was added to the field
+    current: Option<Field>,
+}
+
+impl Fields {
+    fn new() -> Fields {
+        Fields { fields: Vec::new(), current: None }
+    }
+
+    fn push_str(&mut self, text: &str, quoted: bool) {
+        // Only quotes make a field out of nothing
+        if text.is_empty() && !quoted {
+            return;
+        }
+        let field = self.current.get_or_insert_with(Field::default);
+        field.text.push_str(text);
+        if quoted {
+            field.pattern.push_str(glob::escape(text).as_str());
+        } else {
+            field.pattern.push_str(text);
+            field.glob |= glob::has_magic(text);
+        }
+    }
+
+    fn end_field(&mut self) {
+        if let Some(field) = self.current.take() {
+            self.fields.push(field);
+        }
+    }
+
+    // Splits an unquoted value on whitespace, joining its first and last fields with
+    // whatever comes before and after it in the word
+    fn push_split(&mut self, value: &str) {
+        if value.starts_with(char::is_whitespace) {
+            self.end_field();
+        }
+        for (index, field) in value.split_whitespace().enumerate() {
+            if index > 0 {
+                self.end_field();
+            }
+            self.push_str(field, false);
+        }
+        if value.ends_with(char::is_whitespace) {
+            self.end_field();
+        }
+    }
+
+    // Replaces every field with an unquoted pattern by the paths it matches. What
+    // happens to a pattern matching nothing depends on nullglob and failglob.
+    fn finish(mut self, terminal: &Terminal) -> Result<Vec<String>, String> {
+        self.end_field();
+        let mut expanded: Vec<String> = Vec::new();
+        for field in self.fields {
+            if !field.glob {
+                expanded.push(field.text);
+                continue;
+            }
+            let paths = glob::expand(field.pattern.as_str(), terminal.working_dir.as_str());
+            if !paths.is_empty() {
+                expanded.extend(paths);
+            } else if terminal.options.is_set(ShellOption::FailGlob) {
+                return Err(format!("No match: {}", field.text));
+            } else if !terminal.options.is_set(ShellOption::NullGlob) {
+                expanded.push(field.text);
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+/// Expands a leading `~`, the variables and commands of a word, then any filename
+/// patterns. Unquoted values are split into separate fields on whitespace and vanish
+/// when empty, while double quoted ones always stay in one field. Quoted pattern
+/// characters only match themselves.
+pub(crate) fn expand_word(word: &Word, terminal: &mut Terminal) -> Result<Vec<String>, String> {
+    let mut fields = Fields::new();
+    expand_parts(word, terminal, &mut fields)?;
+    fields.finish(terminal)
+}
+
+/// Expands the variables and commands of a word into a single string without any splitting, as done
+/// for the value of an assignment.
+pub(crate) fn expand_to_string(word: &Word, terminal: &mut Terminal) -> Result<String, String> {
+    let mut value = String::new();
+    for part in &word.parts {
+        match part.quoting {
+            Quoting::Literal => value.push_str(part.text.as_str()),
+            Quoting::Unquoted | Quoting::Double => value.push_str(expand_text(part.text.as_str(), terminal)?.as_str()),
+        }
+    }
+    Ok(value)
+}
+
+/// Expands the variables and commands of a word into a pattern to match a single string
+/// against, as done for the patterns of a case. Anything quoted only matches itself.
+pub(crate) fn expand_pattern(word: &Word, terminal: &mut Terminal) -> Result<String, String> {
+    let mut pattern = String::new();
+    for part in &word.parts {
+        match part.quoting {
+            Quoting::Literal => pattern.push_str(glob::escape(part.text.as_str()).as_str()),
+            Quoting::Double => pattern.push_str(glob::escape(expand_text(part.text.as_str(), terminal)?.as_str()).as_str()),
+            Quoting::Unquoted => pattern.push_str(expand_text(part.text.as_str(), terminal)?.as_str()),
+        }
+    }
+    Ok(pattern)
+}
+
+/// Expands every word of a parsed stage, leaving a stage ready to run.
+pub(crate) fn expand_stage(stage: Stage<Word>, terminal: &mut Terminal) -> Result<Stage, String> {
+    let mut assignments: Vec<(String, String)> = Vec::new();
+    for (name, value) in &stage.assignments {
+        assignments.push((name.clone(), expand_to_string(value, terminal)?));
+    }
+    let mut words: Vec<String> = Vec::new();
+    for word in &stage.words {
+        words.extend(expand_word(word, terminal)?);
+    }
+    let mut redirects: Vec<Redirect> = Vec::new();
+    for redirect in stage.redirects {
+        let target = match &redirect.target {
+            Some(target) => {
+                let mut fields = expand_word(target, terminal)?;
+                if fields.len() != 1 {
+                    return Err(format!("{}: ambiguous redirect", target.to_unexpanded_string()));
+                }
+                fields.pop()
+            }
+            None => None,
+        };
+        redirects.push(Redirect { kind: redirect.kind, target });
+    }
+    Ok(Stage { assignments, words, redirects, compound: stage.compound })
+}
+
+fn expand_parts(word: &Word, terminal: &mut Terminal, fields: &mut Fields) -> Result<(), String> {
+    for (index, part) in word.parts.iter().enumerate() {
+        match part.quoting {
+            Quoting::Literal => fields.push_str(part.text.as_str(), true),
+            // "$@" is every positional parameter in a field of its own, and no field at all
+            // when there are none
+            Quoting::Double if matches!(part.text.as_str(), "$@" | "${@}") => {
+                for (index, parameter) in terminal.positional.iter().enumerate() {
+                    if index > 0 {
+                        fields.end_field();
+                    }
+                    fields.push_str(parameter.as_str(), true);
+                }
+            }
+            Quoting::Double => fields.push_str(expand_text(part.text.as_str(), terminal)?.as_str(), true),
+            Quoting::Unquoted if index == 0 => {
+                let rest = expand_tilde(part.text.as_str(), terminal, fields);
+                expand_unquoted(rest, terminal, fields)?
+            }
+            Quoting::Unquoted => expand_unquoted(part.text.as_str(), terminal, fields)?,
+        }
+    }
+    Ok(())
+}
+
+// A `~` starting a word, on its own or before a `/`, is the home directory when HOME is
+// set. Gives the text after it.
+fn expand_tilde<'a>(text: &'a str, terminal: &Terminal, fields: &mut Fields) -> &'a str {
+    let rest = match text.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => return text,
+    };
+    match terminal.variables.get("HOME") {
+        Some(home) => {
+            fields.push_str(home, true);
+            rest
+        }
+        None => text,
+    }
+}
+
+// Unquoted text keeps its literal characters and splits the values it expands to
+fn expand_unquoted(text: &str, terminal: &mut Terminal, fields: &mut Fields) -> Result<(), String> {
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        fields.push_str(&rest[..start], false);
+        let (expansion, after) = parse_expansion(&rest[start..])?;
+        match expansion {
+            Expansion::Literal(text) => fields.push_str(text, false),
+            Expansion::Variable(name) => fields.push_split(lookup(name, terminal).as_str()),
+            Expansion::Command(command) => fields.push_split(terminal.substitute(command)?.as_str()),
+            Expansion::Default(name, default) => match lookup(name, terminal) {
+                value if !value.is_empty() => fields.push_split(value.as_str()),
+                // The default is made of words of its own, quotes included
+                _ => expand_default(default, terminal, fields)?,
+            },
+        }
+        rest = after;
+    }
+    fields.push_str(rest, false);
+    Ok(())
+}
+
+fn expand_default(default: &str, terminal: &mut Terminal, fields: &mut Fields) -> Result<(), String> {
+    let tokens = lexer::tokenize(default).map_err(|error| format!("${{...:-{}}}: {}", default, error))?;
+    for (index, token) in tokens.iter().enumerate() {
+        let word = match token {
+            Token::Word(word) => word,
+            _ => return Err(format!("${{...:-{}}}: bad substitution", default)),
+        };
+        if index > 0 {
+            fields.end_field();
+        }
+        expand_parts(word, terminal, fields)?;
+    }
+    Ok(())
+}
+
+// Double quoted text expands to a single string, the default of `${NAME:-default}`
+// included
+fn expand_text(text: &str, terminal: &mut Terminal) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let (expansion, after) = parse_expansion(&rest[start..])?;
+        match expansion {
+            Expansion::Literal(text) => expanded.push_str(text),
+            Expansion::Variable(name) => expanded.push_str(lookup(name, terminal).as_str()),
+            Expansion::Command(command) => expanded.push_str(terminal.substitute(command)?.as_str()),
+            Expansion::Default(name, default) => match lookup(name, terminal) {
+                value if !value.is_empty() => expanded.push_str(value.as_str()),
+                _ => expanded.push_str(expand_text(default, terminal)?.as_str()),
+            },
+        }
+        rest = after;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+enum Expansion<'a> {
+    // A `$` that does not start an expansion
+    Literal(&'a str),
+    Variable(&'a str),
+    // ${NAME:-default}
+    Default(&'a str, &'a str),
+    // $(command), replaced by what the command writes
+    Command(&'a str),
+}
+
+// Reads the expansion at the start of the text, which begins with a `$`, returning it
+// along with the text after it
+fn parse_expansion(text: &str) -> Result<(Expansion<'_>, &str), String> {
+    let after_dollar = &text[1..];
+    if let Some(command) = after_dollar.strip_prefix('(') {
+        // The lexer only lets through substitutions that are closed
+        let end = lexer::matching_paren(command).ok_or_else(|| format!("{}: bad substitution", text))?;
+        return Ok((Expansion::Command(&command[..end]), &command[end + 1..]));
+    }
+    if let Some(braced) = after_dollar.strip_prefix('{') {
+        // The lexer only lets through braces that are closed
+        let end = matching_brace(braced).ok_or_else(|| format!("{}: bad substitution", text))?;
+        let inner = &braced[..end];
+        let rest = &braced[end + 1..];
+        let (name, default) = match inner.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (inner, None),
+        };
+        if !is_special(name) && !is_positional(name) && !is_valid_name(name) {
+            return Err(format!("${{{}}}: bad substitution", inner));
+        }
+        return Ok(match default {
+            Some(default) => (Expansion::Default(name, default), rest),
+            None => (Expansion::Variable(name), rest),
+        });
+    }
+    // Only one digit is read, `$10` being `$1` followed by a 0
+    if after_dollar.starts_with(is_special_char) || after_dollar.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok((Expansion::Variable(&after_dollar[..1]), &after_dollar[1..]));
+    }
+    let length = after_dollar.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(after_dollar.len());
+    if length == 0 {
+        return Ok((Expansion::Literal("$"), after_dollar));
+    }
+    Ok((Expansion::Variable(&after_dollar[..length]), &after_dollar[length..]))
+}
+
+fn matching_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(index),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_special_char(c: char) -> bool {
+    matches!(c, '?' | '$' | '@' | '#')
+}
+
+// `1`, `2` and so on, or `0` for the name of the shell
+fn is_positional(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_digit())
+}
+
+fn is_special(name: &str) -> bool {
+    name.len() == 1 && name.starts_with(is_special_char)
+}
+
+// The value of a variable, unset variables being empty
+fn lookup(name: &str, terminal: &Terminal) -> String {
+    match name {
+        "?" => terminal.last_status.code().to_string(),
+        "$" => process::id().to_string(),
+        "@" => terminal.positional.join(" "),
+        "#" => terminal.positional.len().to_string(),
+        // Leading zeros still name the same parameter, so ${00} is $0
+        _ if is_positional(name) => match name.parse::<usize>() {
+            Ok(0) => String::from(SHELL_NAME),
+            Ok(number) => terminal.positional.get(number - 1).cloned().unwrap_or_default(),
+            Err(_) => String::new(),
+        },
+        _ => String::from(terminal.variables.get(name).unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+
+    // The expansion at the start of the text written out, followed by the rest of the text
+    fn parse(text: &str) -> (String, &str) {
+        let (expansion, rest) = parse_expansion(text).unwrap();
+        let described = match expansion {
+            Expansion::Literal(text) => format!("literal {}", text),
+            Expansion::Variable(name) => format!("variable {}", name),
+            Expansion::Default(name, default) => format!("default {} {}", name, default),
+            Expansion::Command(command) => format!("command {}", command),
+        };
+        (described, rest)
+    }
+
+    fn expand(line: &str, terminal: &mut Terminal) -> Vec<String> {
+        let mut expanded = Vec::new();
+        for token in tokenize(line).ok().unwrap() {
+            if let Token::Word(word) = token {
+                expanded.extend(expand_word(&word, terminal).unwrap());
+            }
+        }
+        expanded
+    }
+
+    fn terminal() -> Terminal {
+        let mut terminal = Terminal::new(String::from("/"), String::from("/bin"));
+        terminal.variables.set("greeting", String::from("hello  world"));
+        terminal.variables.set("empty", String::new());
+        terminal.positional = vec![String::from("one"), String::from("two")];
+        terminal
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(parse("$HOME/x"), (String::from("variable HOME"), "/x"));
+        assert_eq!(parse("$a_1-b"), (String::from("variable a_1"), "-b"));
+        assert_eq!(parse("${HOME}x"), (String::from("variable HOME"), "x"));
+    }
+
+    #[test]
+    fn parses_special_and_positional_parameters() {
+        assert_eq!(parse("$?x"), (String::from("variable ?"), "x"));
+        assert_eq!(parse("$$"), (String::from("variable $"), ""));
+        assert_eq!(parse("$#"), (String::from("variable #"), ""));
+        // Without braces only one digit is read
+        assert_eq!(parse("$10"), (String::from("variable 1"), "0"));
+        assert_eq!(parse("${10}"), (String::from("variable 10"), ""));
+    }
+
+    #[test]
+    fn parses_defaults() {
+        assert_eq!(parse("${name:-some value}!"), (String::from("default name some value"), "!"));
+        assert_eq!(parse("${name:-${other}}"), (String::from("default name ${other}"), ""));
+    }
+
+    #[test]
+    fn parses_command_substitutions() {
+        assert_eq!(parse("$(echo (a) ')')x"), (String::from("command echo (a) ')'"), "x"));
+    }
+
+    #[test]
+    fn keeps_a_lone_dollar() {
+        assert_eq!(parse("$ x"), (String::from("literal $"), " x"));
+        assert_eq!(parse("$"), (String::from("literal $"), ""));
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        assert!(parse_expansion("${a-b}").is_err());
+        assert!(parse_expansion("${}").is_err());
+    }
+
+    #[test]
+    fn splits_unquoted_values() {
+        let mut terminal = terminal();
+        assert_eq!(expand("echo $greeting \"$greeting\"", &mut terminal), ["echo", "hello", "world", "hello  world"]);
+        assert_eq!(expand("a${greeting}b", &mut terminal), ["ahello", "worldb"]);
+    }
+
+    #[test]
+    fn drops_empty_unquoted_values() {
+        let mut terminal = terminal();
+        assert_eq!(expand("a $empty $unset b \"$empty\"", &mut terminal), ["a", "b", ""]);
+    }
+
+    #[test]
+    fn expands_positional_parameters() {
+        let mut terminal = terminal();
+        assert_eq!(expand("$# $1 $2 $3 $0", &mut terminal), ["2", "one", "two", SHELL_NAME]);
+        assert_eq!(expand("\"$@\"", &mut terminal), ["one", "two"]);
+        assert_eq!(expand("${00} ${02} ${010}", &mut terminal), [SHELL_NAME, "two"]);
+    }
+
+    #[test]
+    fn expands_defaults_only_when_empty() {
+        let mut terminal = terminal();
+        assert_eq!(expand("${empty:-x y} \"${unset:-x y}\" ${1:-z}", &mut terminal), ["x", "y", "x y", "one"]);
+    }
+
+    #[test]
+    fn expands_a_leading_tilde() {
+        let mut terminal = terminal();
+        terminal.variables.set("HOME", String::from("/home/some one"));
+        assert_eq!(expand("~ ~/a x~ ~b '~'/a \\~ a/~", &mut terminal),
+                   ["/home/some one", "/home/some one/a", "x~", "~b", "~/a", "~", "a/~"]);
+    }
+
+    #[test]
+    fn leaves_quoted_text_alone() {
+        let mut terminal = terminal();
+        assert_eq!(expand("'$greeting' \\$1 \"\\$1\"", &mut terminal), ["$greeting", "$1", "$1"]);
+    }
+}
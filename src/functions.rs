@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+use crate::history::{escape, unescape};
+use crate::lexer;
+use crate::list;
+use crate::list::Compound;
+use crate::versioned_file::VersionedFile;
+
+const VERSION: u32 = 2;
+// The line after the header, telling whether the savefunctions option is set
+const SAVING: &str = "savefunctions on";
+const NOT_SAVING: &str = "savefunctions off";
+
+/// A function defined with `name() compound`, run like any other command with its
+/// arguments as the positional parameters.
+#[derive(Clone)]
+pub(crate) struct Function {
+    pub body: Compound,
+    // The definition written out, for listing and saving it
+    pub text: String,
+}
+
+/// Function names and their definitions.
+pub(crate) type Functions = HashMap<String, Function>;
+
+/// The function file starts with a version header and a line telling whether functions
+/// are saved, followed by the escaped definition of one function per line while they
+/// are. Version 1 files, which were only written while functions were saved, hold no
+/// such line.
+pub(crate) struct FunctionFile {
+    file: VersionedFile,
+}
+
+impl FunctionFile {
+    pub fn new(path: PathBuf) -> FunctionFile {
+        FunctionFile { file: VersionedFile::new(path, "functions") }
+    }
+
+    /// Reads the functions from the file, skipping malformed definitions with a warning.
+    /// Gives None when functions are not saved, which is also the case without a file. A
+    /// file written by a newer version is rejected.
+    pub fn load(&self) -> io::Result<Option<Functions>> {
+        let (version, lines) = match self.file.read(1..=VERSION)? {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+        let mut lines = lines.iter();
+        if version == VERSION {
+            match lines.next().map(String::as_str) {
+                Some(SAVING) => {}
+                Some(NOT_SAVING) => return Ok(None),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("{} does not tell whether functions are saved", self.file.path().display()))),
+            }
+        }
+
+        let mut functions: Functions = HashMap::new();
+        for line in lines.filter(|line| !line.is_empty()) {
+            match unescape(line).and_then(|text| parse_definition(text.as_str())) {
+                Some((name, function)) => {
+                    functions.insert(name, function);
+                }
+                None => eprintln!("Ignoring malformed function definition \"{}\"", line),
+            }
+        }
+        Ok(Some(functions))
+    }
+
+    /// Writes every function to the file, replacing it as a whole, and records that
+    /// functions are saved.
+    pub fn save(&self, functions: &Functions) -> io::Result<()> {
+        let mut names: Vec<&String> = functions.keys().collect();
+        names.sort();
+        let mut lines: Vec<String> = vec![String::from(SAVING)];
+        lines.extend(names.into_iter().map(|name| escape(functions[name].text.as_str())));
+        self.file.write(VERSION, lines.as_slice())
+    }
+
+    /// Records that functions are no longer saved, dropping the saved definitions so the
+    /// next session starts without them.
+    pub fn stop_saving(&self) -> io::Result<()> {
+        self.file.write(VERSION, &[String::from(NOT_SAVING)])
+    }
+}
+
+// Parses a saved definition, which has to be a single function definition
+fn parse_definition(text: &str) -> Option<(String, Function)> {
+    let command_list = list::parse(lexer::tokenize(text).ok()?).ok()?;
+    let (_, pipeline) = match command_list.items.as_slice() {
+        [item] => item,
+        _ => return None,
+    };
+    match pipeline.stages.as_slice() {
+        [stage] => match &stage.compound {
+            Some(Compound::Function { name, body, text }) => {
+                Some((name.clone(), Function { body: (**body).clone(), text: text.clone() }))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
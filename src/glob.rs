@@ -0,0 +1,316 @@
+use std::fs;
+use std::path::Path;
+
+/// Whether a pattern contains an unescaped `*`, `?` or `[`.
+pub(crate) fn has_magic(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Escapes the characters a pattern treats specially, so the text only matches itself.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unescape(pattern: &str) -> String {
+    let mut text = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            _ => text.push(c),
+        }
+    }
+    text
+}
+
+/// Finds the paths matching a pattern, relative to the working directory unless the
+/// pattern is absolute, sorted by name. `*` matches any part of a name, `?` a single
+/// character and `[abc]`, `[a-z]` or `[!abc]` one of a set of characters. A `**`
+/// component matches any number of directories. Hidden files only match a pattern that
+/// starts with a `.` itself.
+pub(crate) fn expand(pattern: &str, working_dir: &str) -> Vec<String> {
+    let only_dirs = pattern.ends_with('/');
+    let components: Vec<&str> = pattern.split('/').filter(|component| !component.is_empty()).collect();
+    let mut paths: Vec<String> = vec![String::from(if pattern.starts_with('/') { "/" } else { "" })];
+
+    for (index, component) in components.iter().enumerate() {
+        let last = index + 1 == components.len();
+        let mut next: Vec<String> = Vec::new();
+        for prefix in &paths {
+            let dir = Path::new(working_dir).join(if prefix.is_empty() { "." } else { prefix.as_str() });
+            if *component == "**" {
+                // Further components match inside the prefix itself as well
+                if !last {
+                    next.push(prefix.clone());
+                }
+                walk(dir.as_path(), prefix, !last, &mut next);
+            } else if !has_magic(component) {
+                next.push(join(prefix, unescape(component).as_str()));
+            } else {
+                let pattern: Vec<char> = component.chars().collect();
+                for (name, is_dir) in read_dir(dir.as_path()) {
+                    let hidden_allowed = !name.starts_with('.') || component.starts_with('.');
+                    if hidden_allowed && (is_dir || last) && matches(&pattern, &name.chars().collect::<Vec<char>>()) {
+                        next.push(join(prefix, name.as_str()));
+                    }
+                }
+            }
+        }
+        paths = next;
+    }
+
+    let mut paths: Vec<String> = paths.into_iter()
+        .filter(|path| !path.is_empty())
+        .filter_map(|path| {
+            let metadata = fs::metadata(Path::new(working_dir).join(&path)).ok()?;
+            match only_dirs {
+                true if metadata.is_dir() => Some(format!("{}/", path)),
+                true => None,
+                false => Some(path),
+            }
+        })
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() || prefix.ends_with('/') {
+        return format!("{}{}", prefix, name);
+    }
+    format!("{}/{}", prefix, name)
+}
+
+// The names in a directory with whether each is a directory, nothing if it can't be read
+fn read_dir(dir: &Path) -> Vec<(String, bool)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries.filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let is_dir = entry.path().is_dir();
+            entry.file_name().into_string().ok().map(|name| (name, is_dir))
+        })
+        .collect()
+}
+
+// Collects everything below a directory that is not hidden, or only the directories
+fn walk(dir: &Path, prefix: &str, only_dirs: bool, paths: &mut Vec<String>) {
+    for (name, is_dir) in read_dir(dir) {
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = join(prefix, name.as_str());
+        if is_dir {
+            // Symbolic links to directories are not followed, they could form a loop
+            let is_link = fs::symlink_metadata(dir.join(&name)).map(|metadata| metadata.is_symlink()).unwrap_or(true);
+            if !is_link {
+                walk(dir.join(&name).as_path(), path.as_str(), only_dirs, paths);
+            }
+        }
+        if is_dir || !only_dirs {
+            paths.push(path);
+        }
+    }
+}
+
+/// Whether a whole text matches a pattern, where `*` matches `/` as well.
+pub(crate) fn is_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches(pattern.as_slice(), text.as_slice())
+}
+
+/// Matches a whole name against a single pattern component.
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` when the rest fails to match
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match match_class(&pattern[p..], name[n]) {
+                Some((true, length)) => Some(p + length),
+                Some((false, _)) => None,
+                // A `[` without a closing `]` is an ordinary character
+                None if name[n] == '[' => Some(p + 1),
+                None => None,
+            },
+            Some('\\') if pattern.get(p + 1) == Some(&name[n]) => Some(p + 2),
+            Some(c) if *c == name[n] => Some(p + 1),
+            _ => None,
+        };
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                n += 1;
+            }
+            (None, Some((star, matched))) => {
+                p = star + 1;
+                n = matched + 1;
+                backtrack = Some((star, matched + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Matches a character against the `[...]` at the start of the pattern, returning whether
+// it matched and the length of the class, or None if the class is never closed
+fn match_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut index = 1;
+    let negated = matches!(pattern.get(index), Some('!' | '^'));
+    if negated {
+        index += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let start = match pattern.get(index) {
+            // A `]` straight after the opening is part of the set
+            Some(']') if !first => return Some((matched != negated, index + 1)),
+            Some('\\') => {
+                index += 1;
+                *pattern.get(index)?
+            }
+            Some(start) => *start,
+            None => return None,
+        };
+        first = false;
+        index += 1;
+        if pattern.get(index) == Some(&'-') && pattern.get(index + 1).map(|end| *end != ']').unwrap_or(false) {
+            let end = pattern[index + 1];
+            matched |= start <= c && c <= end;
+            index += 2;
+        } else {
+            matched |= start == c;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    // A fresh directory holding the given files, a name ending in `/` being a directory
+    fn directory(test: &str, files: &[&str]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust-terminal-glob-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            match file.strip_suffix('/') {
+                Some(name) => fs::create_dir_all(dir.join(name)).unwrap(),
+                None => fs::write(dir.join(file), "").unwrap(),
+            }
+        }
+        dir
+    }
+
+    fn expand_in(dir: &Path, pattern: &str) -> Vec<String> {
+        expand(pattern, dir.to_str().unwrap())
+    }
+
+    #[test]
+    fn finds_unescaped_magic() {
+        assert!(has_magic("*.rs"));
+        assert!(has_magic("a?"));
+        assert!(has_magic("[ab]"));
+        assert!(!has_magic("plain"));
+        assert!(!has_magic(r"\*\?\["));
+        assert!(has_magic(r"\\*"));
+    }
+
+    #[test]
+    fn escaped_text_only_matches_itself() {
+        let text = r"a*b?[c]\d";
+        assert!(!has_magic(escape(text).as_str()));
+        assert!(is_match(escape(text).as_str(), text));
+        assert!(!is_match(escape(text).as_str(), "aXb?[c]\\d"));
+        assert_eq!(unescape(escape(text).as_str()), text);
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(is_match("*.rs", "main.rs"));
+        assert!(is_match("*", ""));
+        assert!(is_match("a*b*c", "aXXbYc"));
+        assert!(!is_match("a*b*c", "aXXbY"));
+        assert!(is_match("?at", "cat"));
+        assert!(!is_match("?at", "at"));
+        // A whole text is matched, where `*` crosses `/`
+        assert!(is_match("src/*", "src/a/b.rs"));
+        assert!(!is_match("src", "src/a"));
+    }
+
+    #[test]
+    fn matches_classes() {
+        assert!(is_match("[abc]x", "bx"));
+        assert!(!is_match("[abc]x", "dx"));
+        assert!(is_match("[a-c]", "b"));
+        assert!(!is_match("[a-c]", "d"));
+        assert!(is_match("[!a-c]", "d"));
+        assert!(!is_match("[!a-c]", "a"));
+    }
+
+    #[test]
+    fn expands_sorted_matches() {
+        let dir = directory("sorted", &["b.rs", "a.rs", "c.txt", ".hidden.rs"]);
+        assert_eq!(expand_in(&dir, "*.rs"), ["a.rs", "b.rs"]);
+        assert_eq!(expand_in(&dir, ".*.rs"), [".hidden.rs"]);
+        assert_eq!(expand_in(&dir, "?.txt"), ["c.txt"]);
+        assert!(expand_in(&dir, "*.md").is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expands_directories() {
+        let dir = directory("directories", &["src/", "src/main.rs", "tests/", "tests/a.rs", "notes"]);
+        assert_eq!(expand_in(&dir, "*/"), ["src/", "tests/"]);
+        assert_eq!(expand_in(&dir, "*/*.rs"), ["src/main.rs", "tests/a.rs"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expands_any_depth() {
+        let dir = directory("depth", &["a.rs", "x/", "x/b.rs", "x/y/", "x/y/c.rs", "x/.z/", "x/.z/d.rs"]);
+        assert_eq!(expand_in(&dir, "**/*.rs"), ["a.rs", "x/b.rs", "x/y/c.rs"]);
+        assert_eq!(expand_in(&dir, "**/"), ["x/", "x/y/"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expands_absolute_patterns() {
+        let dir = directory("absolute", &["one", "two"]);
+        let pattern = format!("{}/t*", escape(dir.to_str().unwrap()));
+        assert_eq!(expand(pattern.as_str(), "/"), [format!("{}/two", dir.display())]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,471 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::command::Command;
+use crate::glob;
+use crate::versioned_file;
+use crate::versioned_file::VersionedFile;
+
+const VERSION: u32 = 2;
+const EXTERNAL_KEY: &str = "external";
+const FUNCTION_KEY: &str = "function";
+
+pub const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+/// A command line in the history.
+pub(crate) struct HistoryEntry {
+    pub command: Command,
+    pub line: String,
+    // How the line ran, unknown for entries from older history files and lines
+    // that were only printed
+    pub details: Option<RunDetails>,
+    // Whether the entry is in the history file, only false when writing it failed
+    pub saved: bool,
+}
+
+/// When, where and how a line of the history ran.
+#[derive(Clone)]
+pub(crate) struct RunDetails {
+    // Seconds since the epoch when it started
+    pub started: i64,
+    pub working_dir: String,
+    pub status: i32,
+    pub duration: Duration,
+}
+
+impl HistoryEntry {
+    pub fn new(command: Command, line: &str, details: Option<RunDetails>) -> HistoryEntry {
+        HistoryEntry { command, line: String::from(line), details, saved: false }
+    }
+
+    /// Text that is the same for two entries only when they are the same line run at the
+    /// same time and place, as when both were read from the history file.
+    pub fn identity(&self) -> String {
+        encode_entry(self)
+    }
+}
+
+impl Clone for HistoryEntry {
+    fn clone(&self) -> HistoryEntry {
+        HistoryEntry {
+            command: Command::from(&self.command),
+            line: self.line.clone(),
+            details: self.details.clone(),
+            saved: self.saved,
+        }
+    }
+}
+
+/// Which lines are left out of the history, set the way bash does with the HISTCONTROL
+/// and HISTIGNORE variables. HISTCONTROL is a colon separated list of `ignorespace` to
+/// leave out lines starting with a space, `ignoredups` to leave out a line repeating the
+/// one before it, `ignoreboth` for both of them and `erasedups` to remove earlier copies
+/// of a line as it is recorded. HISTIGNORE is a colon separated list of patterns matched
+/// against the whole line, where `&` stands for the line before it.
+pub(crate) struct HistoryFilter {
+    ignore_space: bool,
+    ignore_dups: bool,
+    pub erase_dups: bool,
+    ignore_patterns: Vec<String>,
+}
+
+impl HistoryFilter {
+    pub fn new(control: &str, ignore: &str) -> HistoryFilter {
+        let control: Vec<&str> = control.split(':').collect();
+        HistoryFilter {
+            ignore_space: control.contains(&"ignorespace") || control.contains(&"ignoreboth"),
+            ignore_dups: control.contains(&"ignoredups") || control.contains(&"ignoreboth"),
+            erase_dups: control.contains(&"erasedups"),
+            ignore_patterns: ignore.split(':').filter(|pattern| !pattern.is_empty()).map(String::from).collect(),
+        }
+    }
+
+    /// Whether a line, as it was typed, is left out of the history.
+    pub fn ignores(&self, line: &str, previous: Option<&str>) -> bool {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || (self.ignore_space && line.starts_with(' ')) {
+            return true;
+        }
+        if self.ignore_dups && previous == Some(trimmed) {
+            return true;
+        }
+        self.ignore_patterns.iter().any(|pattern| match pattern.as_str() {
+            "&" => previous == Some(trimmed),
+            pattern => glob::is_match(pattern, trimmed),
+        })
+    }
+}
+
+/// The history file stores one entry per line as `<key>\t<command line>`, where the key
+/// is the builtin name (or `external`) so entries keep their meaning if the `Command`
+/// enum is reordered or extended, followed by `\t<started>\t<milliseconds>\t<status>\t<working
+/// directory>` when it is known how the line ran. Text is escaped so it always fits on one
+/// line. Version 1 files, which had no run details, are read the same way.
+pub(crate) struct HistoryFile {
+    file: VersionedFile,
+    max_size: usize,
+}
+
+impl HistoryFile {
+    pub fn new(path: PathBuf, max_size: usize) -> HistoryFile {
+        HistoryFile { file: VersionedFile::new(path, "history"), max_size }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Reads every well formed entry from the file, skipping (and reporting) any line
+    /// that can not be understood. A file written by a newer version is rejected.
+    pub fn load(&self) -> io::Result<Vec<HistoryEntry>> {
+        let mut file = match self.open_locked(OpenOptions::new().read(true), libc::LOCK_SH) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        drop(file);
+
+        let mut malformed = 0;
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        for line in self.entry_lines(contents.as_str())?.iter().filter(|line| !line.is_empty()) {
+            match decode_entry(line) {
+                Some(entry) => entries.push(entry),
+                None => malformed += 1,
+            }
+        }
+        if malformed > 0 {
+            eprintln!("Skipped {} malformed line(s) in {}", malformed, self.file.path().display());
+        }
+        let overflow = entries.len().saturating_sub(self.max_size);
+        if overflow > 0 {
+            // Appends never trim the file, so it is brought back to size here
+            self.rewrite(|_| ())?;
+        }
+        entries.drain(..overflow);
+        Ok(entries)
+    }
+
+    /// Appends an entry while holding an exclusive lock, so concurrent shells never
+    /// interleave partial writes. The file is opened for appending and never truncated,
+    /// so a failed write can at worst lose the entry itself. The file is trimmed to the
+    /// maximum size when it is next loaded.
+    pub fn append(&self, entry: &HistoryEntry) -> io::Result<()> {
+        self.append_all(&[entry])
+    }
+
+    /// Appends an entry after removing every earlier entry of the same line, for the
+    /// `erasedups` setting.
+    pub fn append_erasing(&self, entry: &HistoryEntry) -> io::Result<()> {
+        self.rewrite(|lines| {
+            lines.retain(|line| decode_entry(line).is_none_or(|known| known.line != entry.line));
+            lines.push(encode_entry(entry));
+        })
+    }
+
+    /// Appends several entries at once, in order.
+    pub fn append_all(&self, entries: &[&HistoryEntry]) -> io::Result<()> {
+        let mut file = self.open_locked(OpenOptions::new().append(true).create(true), libc::LOCK_EX)?;
+        let mut output = String::new();
+        if file.metadata()?.len() == 0 {
+            output.push_str(self.file.header(VERSION).as_str());
+        }
+        for entry in entries {
+            output.push_str(encode_entry(entry).as_str());
+            output.push('\n');
+        }
+        file.write_all(output.as_bytes())?;
+        file.flush()
+    }
+
+    /// Removes the most recent line of the file holding the entry.
+    pub fn remove(&self, entry: &HistoryEntry) -> io::Result<()> {
+        let encoded = encode_entry(entry);
+        self.rewrite(|lines| {
+            if let Some(position) = lines.iter().rposition(|line| *line == encoded) {
+                lines.remove(position);
+            }
+        })
+    }
+
+    /// Removes every entry from the file.
+    pub fn clear(&self) -> io::Result<()> {
+        self.rewrite(|lines| lines.clear())
+    }
+
+    // Changes the lines of the file while holding an exclusive lock. The new contents
+    // are written to a temporary file that is renamed over the history file, so the
+    // old history stays intact if the write fails. Well formed lines with a key this
+    // version does not know are kept as they are, anything else is dropped. A file
+    // written by a newer version is left alone.
+    fn rewrite<F: FnOnce(&mut Vec<String>)>(&self, change: F) -> io::Result<()> {
+        let mut file = self.open_locked(OpenOptions::new().read(true).append(true).create(true), libc::LOCK_EX)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut lines = self.entry_lines(contents.as_str())?;
+        lines.retain(|line| is_well_formed(line));
+        change(&mut lines);
+        let overflow = lines.len().saturating_sub(self.max_size);
+
+        let mut output = self.file.header(VERSION);
+        for line in &lines[overflow..] {
+            output.push_str(line);
+            output.push('\n');
+        }
+        let written = versioned_file::replace(self.file.path(), output.as_str());
+        // The lock on the replaced file is only released once the new one is in place
+        drop(file);
+        written
+    }
+
+    // The lines after the header of the file's contents. A file only just created is
+    // still empty.
+    fn entry_lines(&self, contents: &str) -> io::Result<Vec<String>> {
+        if contents.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.file.parse(contents, 1..=VERSION).map(|(_, lines)| lines)
+    }
+
+    // Opens the history file and locks it with flock. Another shell may have replaced
+    // the file while this one waited for the lock, in which case the new file is
+    // opened instead so nothing is written to the replaced one.
+    fn open_locked(&self, options: &OpenOptions, operation: libc::c_int) -> io::Result<File> {
+        loop {
+            let file = options.open(self.file.path())?;
+            // SAFETY: the descriptor stays open for the duration of the call
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let opened = file.metadata()?;
+            match fs::metadata(self.file.path()) {
+                Ok(current) if current.dev() == opened.dev() && current.ino() == opened.ino() => return Ok(file),
+                Ok(_) => continue,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+fn encode_entry(entry: &HistoryEntry) -> String {
+    let key = match &entry.command {
+        Command::External(_) => String::from(EXTERNAL_KEY),
+        Command::Function(_) => String::from(FUNCTION_KEY),
+        command => command.to_string(),
+    };
+    let mut encoded = format!("{}\t{}", key, escape(entry.line.as_str()));
+    if let Some(details) = &entry.details {
+        encoded.push_str(format!("\t{}\t{}\t{}\t{}", details.started, details.duration.as_millis(),
+                                 details.status, escape(details.working_dir.as_str())).as_str());
+    }
+    encoded
+}
+
+fn decode_entry(line: &str) -> Option<HistoryEntry> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let cmd_string_line = unescape(fields.get(1)?)?;
+    let command = if fields[0] == EXTERNAL_KEY {
+        Command::External(String::from(cmd_string_line.split_whitespace().next()?))
+    } else if fields[0] == FUNCTION_KEY {
+        // A call starts with the name of the function, as does a definition such as `f() {`
+        let name = cmd_string_line.split(|c: char| c.is_whitespace() || c == '(').next().filter(|name| !name.is_empty())?;
+        Command::Function(String::from(name))
+    } else {
+        Command::get_command_enum(fields[0])?
+    };
+    let details = match fields[2..] {
+        [] => None,
+        [started, milliseconds, status, working_dir] => Some(RunDetails {
+            started: started.parse().ok()?,
+            working_dir: unescape(working_dir)?,
+            status: status.parse().ok()?,
+            duration: Duration::from_millis(milliseconds.parse().ok()?),
+        }),
+        _ => return None,
+    };
+    let mut entry = HistoryEntry::new(command, cmd_string_line.as_str(), details);
+    entry.saved = true;
+    Some(entry)
+}
+
+fn is_well_formed(line: &str) -> bool {
+    let fields: Vec<&str> = line.split('\t').collect();
+    fields.len() >= 2 && !fields[0].is_empty() && fields.iter().skip(1).all(|field| unescape(field).is_some())
+}
+
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn unescape(value: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            't' => unescaped.push('\t'),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A history file of the given contents, unique to the test
+    fn history_file(name: &str, contents: &str) -> HistoryFile {
+        let path = std::env::temp_dir().join(format!("rust-terminal-{}-{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        HistoryFile::new(path, DEFAULT_HISTORY_SIZE)
+    }
+
+    #[test]
+    fn decodes_version_1_lines() {
+        let entry = decode_entry("cd\tcd /tmp").unwrap();
+        assert!(matches!(entry.command, Command::ChangeDir));
+        assert_eq!(entry.line, "cd /tmp");
+        assert!(entry.details.is_none());
+        let entry = decode_entry("external\techo a\\tb").unwrap();
+        assert!(matches!(entry.command, Command::External(program) if program == "echo"));
+        assert_eq!(entry.line, "echo a\tb");
+    }
+
+    #[test]
+    fn decodes_version_2_lines() {
+        let entry = decode_entry("function\tgreet world\t1700000000\t1500\t2\t/home/me").unwrap();
+        assert!(matches!(entry.command, Command::Function(name) if name == "greet"));
+        let details = entry.details.unwrap();
+        assert_eq!(details.started, 1700000000);
+        assert_eq!(details.duration, Duration::from_millis(1500));
+        assert_eq!(details.status, 2);
+        assert_eq!(details.working_dir, "/home/me");
+    }
+
+    #[test]
+    fn decodes_function_definitions_under_the_function() {
+        for line in ["function\tf() { local x=1; }", "function\tf () { :; }", "function\tf 1 2"] {
+            let entry = decode_entry(line).unwrap();
+            assert!(matches!(&entry.command, Command::Function(name) if name == "f"), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn encodes_what_it_decodes() {
+        for line in ["cd\tcd /tmp", "external\tls\t1700000000\t3\t0\t/a\\tb"] {
+            assert_eq!(encode_entry(&decode_entry(line).unwrap()), line);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in ["cd", "no-such-builtin\tx", "external\t", "cd\tbad \\q escape", "cd\tcd\t1\t2\t3",
+                     "cd\tcd\tsoon\t2\t3\t/", "cd\tcd\t1\t2\t3\t/\textra"] {
+            assert!(decode_entry(line).is_none(), "{:?} was decoded", line);
+        }
+    }
+
+    #[test]
+    fn loads_both_versions() {
+        for (name, contents) in [("v1", "#rust-terminal history v1\ncd\tcd /\n"),
+                                 ("v2", "#rust-terminal history v2\ncd\tcd /\t1\t2\t0\t/\nbad\n")] {
+            let file = history_file(name, contents);
+            assert_eq!(file.load().unwrap().len(), 1);
+            let _ = fs::remove_file(file.file.path());
+        }
+    }
+
+    #[test]
+    fn leaves_a_newer_file_alone() {
+        let contents = "#rust-terminal history v3\ncd\tcd /\tnew field\n";
+        let file = history_file("v3", contents);
+        assert_eq!(file.load().err().map(|error| error.kind()), Some(io::ErrorKind::InvalidData));
+        assert!(file.clear().is_err());
+        assert_eq!(fs::read_to_string(file.file.path()).unwrap(), contents);
+        let _ = fs::remove_file(file.file.path());
+    }
+
+    #[test]
+    fn always_ignores_blank_lines() {
+        let filter = HistoryFilter::new("", "");
+        assert!(filter.ignores("   ", None));
+        assert!(!filter.ignores(" ls", None));
+        assert!(!filter.ignores("ls", Some("ls")));
+    }
+
+    #[test]
+    fn ignores_lines_starting_with_a_space() {
+        let filter = HistoryFilter::new("ignorespace", "");
+        assert!(filter.ignores(" ls", None));
+        assert!(!filter.ignores("ls ", None));
+        assert!(!filter.ignores("ls", Some("ls")));
+    }
+
+    #[test]
+    fn ignores_a_line_repeating_the_one_before() {
+        let filter = HistoryFilter::new("ignoredups", "");
+        assert!(filter.ignores("ls", Some("ls")));
+        assert!(filter.ignores("ls  ", Some("ls")));
+        assert!(!filter.ignores("ls -l", Some("ls")));
+        assert!(!filter.ignores(" ls", None));
+    }
+
+    #[test]
+    fn ignores_both() {
+        let filter = HistoryFilter::new("ignoreboth", "");
+        assert!(filter.ignores(" ls", None));
+        assert!(filter.ignores("ls", Some("ls")));
+        assert!(!filter.erase_dups);
+    }
+
+    #[test]
+    fn erases_duplicates_instead_of_ignoring_them() {
+        let filter = HistoryFilter::new("ignorespace:erasedups", "");
+        assert!(filter.erase_dups);
+        assert!(!filter.ignores("ls", Some("ls")));
+        assert!(filter.ignores(" ls", None));
+    }
+
+    #[test]
+    fn ignores_lines_matching_a_pattern() {
+        let filter = HistoryFilter::new("", "ls:cd *::exit");
+        assert!(filter.ignores("ls", None));
+        assert!(filter.ignores(" cd /tmp ", None));
+        assert!(filter.ignores("exit", None));
+        assert!(!filter.ignores("ls -l", None));
+        assert!(!filter.ignores("cd", None));
+    }
+
+    #[test]
+    fn ampersand_stands_for_the_line_before() {
+        let filter = HistoryFilter::new("", "&");
+        assert!(filter.ignores("make", Some("make")));
+        assert!(!filter.ignores("make", Some("make test")));
+        assert!(!filter.ignores("make", None));
+    }
+}
@@ -0,0 +1,435 @@
+/// A line with its history references replaced.
+pub(crate) struct Expanded {
+    pub line: String,
+    // Whether anything was replaced, in which case the line is echoed before it runs
+    pub changed: bool,
+    // Whether a `:p` modifier asked for the line to be printed instead of run
+    pub print_only: bool,
+}
+
+/// Replaces the history references of a line the way bash does before it is parsed.
+/// A reference is an event, optionally followed by a word designator and modifiers:
+///
+/// * events `!!`, `!n`, `!-n`, `!prefix` and `!?substring?`
+/// * word designators `:n`, `:x-y`, `:x-`, `:x*`, `^`, `$` and `*`, where `!$`, `!^`,
+///   `!*` and `!:2-3` refer to the previous command
+/// * modifiers `:h`, `:t`, `:r`, `:s/old/new/`, `:gs/old/new/` and `:p`
+///
+/// A line starting with `^old^new^` runs the previous command with `old` replaced by
+/// `new`. Nothing is replaced between single quotes or after a backslash, nor when
+/// `!` is followed by a blank, `=` or `(`.
+pub(crate) fn expand_history(line: &str, history: &[String]) -> Result<Expanded, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut expanded = Expanded { line: String::new(), changed: false, print_only: false };
+    let mut index = 0;
+    if chars.first() == Some(&'^') {
+        let previous = history.last().ok_or_else(|| String::from("^: event not found"))?;
+        let (substitution, next) = parse_substitution(&chars, 0)?;
+        expanded.line.push_str(substitution.apply(previous)?.as_str());
+        expanded.changed = true;
+        index = next;
+    }
+
+    let mut single_quoted = false;
+    let mut double_quoted = false;
+    while index < chars.len() {
+        let c = chars[index];
+        match c {
+            '\\' if !single_quoted => {
+                expanded.line.push(c);
+                expanded.line.extend(chars.get(index + 1));
+                index += 2;
+                continue;
+            }
+            '\'' if !double_quoted => single_quoted = !single_quoted,
+            '"' if !single_quoted => double_quoted = !double_quoted,
+            '!' if !single_quoted && starts_reference(chars.get(index + 1), double_quoted) => {
+                let (text, print_only, next) = expand_reference(&chars, index, history)?;
+                expanded.line.push_str(text.as_str());
+                expanded.changed = true;
+                expanded.print_only |= print_only;
+                index = next;
+                continue;
+            }
+            _ => {}
+        }
+        expanded.line.push(c);
+        index += 1;
+    }
+    Ok(expanded)
+}
+
+// Whether a `!` followed by the given character starts a history reference
+fn starts_reference(next: Option<&char>, double_quoted: bool) -> bool {
+    match next {
+        None => false,
+        Some(c) if c.is_whitespace() || matches!(c, '=' | '(') => false,
+        Some('"') => !double_quoted,
+        Some(_) => true,
+    }
+}
+
+// Expands the reference starting with the `!` at `start`, giving its text, whether it
+// is only to be printed and where the rest of the line starts
+fn expand_reference(chars: &[char], start: usize, history: &[String]) -> Result<(String, bool, usize), String> {
+    let (event, mut index) = find_event(chars, start + 1, history)?;
+    let mut text = String::from(event.trim());
+
+    let designator_follows = match chars.get(index) {
+        Some('^' | '$' | '*') => true,
+        Some(':') => matches!(chars.get(index + 1), Some(c) if c.is_ascii_digit() || matches!(c, '^' | '$' | '*' | '-')),
+        _ => false,
+    };
+    if designator_follows {
+        if chars[index] == ':' {
+            index += 1;
+        }
+        let words = split_words(event);
+        let (first, last, next) = parse_designator(chars, index, words.len())
+            .ok_or_else(|| {
+                let reference: String = chars[start..].iter().take_while(|c| !c.is_whitespace()).collect();
+                format!("{}: bad word specifier", reference)
+            })?;
+        text = if first > last { String::new() } else { words[first..=last].join(" ") };
+        index = next;
+    }
+
+    let mut print_only = false;
+    while chars.get(index) == Some(&':') {
+        match chars.get(index + 1) {
+            Some('h') => text = head(text.as_str()),
+            Some('t') => text = tail(text.as_str()),
+            Some('r') => text = root(text.as_str()),
+            Some('p') => print_only = true,
+            Some('s') | Some('g') => {
+                let (substitution, next) = parse_substitution(chars, index + 1)?;
+                text = substitution.apply(text.as_str())?;
+                index = next;
+                continue;
+            }
+            // Anything else is not part of the reference
+            _ => break,
+        }
+        index += 2;
+    }
+    Ok((text, print_only, index))
+}
+
+// Finds the history entry an event refers to, the event starting after its `!`
+fn find_event<'a>(chars: &[char], start: usize, history: &'a [String]) -> Result<(&'a str, usize), String> {
+    let (found, end) = match chars[start] {
+        '!' => (history.last(), start + 1),
+        // A word designator on its own refers to the previous command
+        '^' | '$' | '*' | ':' => (history.last(), start),
+        '-' | '0'..='9' => {
+            let digits_start = if chars[start] == '-' { start + 1 } else { start };
+            let end = digits_start + chars[digits_start..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let number: String = chars[digits_start..end].iter().collect();
+            let entry = match (number.parse::<usize>(), chars[start] == '-') {
+                (Ok(back), true) if back > 0 => history.len().checked_sub(back).and_then(|index| history.get(index)),
+                (Ok(number), false) if number > 0 => history.get(number - 1),
+                _ => None,
+            };
+            (entry, end)
+        }
+        '?' => {
+            let length = chars[start + 1..].iter().take_while(|c| **c != '?').count();
+            let substring: String = chars[start + 1..start + 1 + length].iter().collect();
+            let end = (start + 2 + length).min(chars.len());
+            (history.iter().rev().find(|entry| entry.contains(substring.as_str())), end)
+        }
+        _ => {
+            let length = chars[start..].iter()
+                .take_while(|c| !c.is_whitespace() && !matches!(c, ':' | ';' | '|' | '&' | '<' | '>' | '(' | ')' | '\'' | '"'))
+                .count();
+            let prefix: String = chars[start..start + length].iter().collect();
+            let entry = history.iter().rev().find(|entry| entry.trim_start().starts_with(prefix.as_str()));
+            (entry.filter(|_| !prefix.is_empty()), start + length)
+        }
+    };
+    match found {
+        Some(entry) => Ok((entry.as_str(), end)),
+        None => Err(format!("!{}: event not found", chars[start..end].iter().collect::<String>())),
+    }
+}
+
+// Parses a word designator into the first and last word it selects, the command being
+// word 0, along with where it ends. The range is empty when the first is past the last.
+fn parse_designator(chars: &[char], start: usize, word_count: usize) -> Option<(usize, usize, usize)> {
+    let last_word = word_count.checked_sub(1)?;
+    let number_at = |index: usize| -> (Option<usize>, usize) {
+        let length = chars[index..].iter().take_while(|c| c.is_ascii_digit()).count();
+        let number: String = chars[index..index + length].iter().collect();
+        (number.parse::<usize>().ok(), index + length)
+    };
+    let (first, last, end) = match chars.get(start)? {
+        '^' => (1, 1, start + 1),
+        '$' => (last_word, last_word, start + 1),
+        '*' => (1, last_word, start + 1),
+        '-' => {
+            let (last, end) = match chars.get(start + 1) {
+                Some('$') => (Some(last_word), start + 2),
+                _ => number_at(start + 1),
+            };
+            (0, last?, end)
+        }
+        _ => {
+            let (first, after) = number_at(start);
+            let first = first?;
+            match chars.get(after) {
+                Some('*') => (first, last_word, after + 1),
+                Some('-') => match chars.get(after + 1) {
+                    Some('$') => (first, last_word, after + 2),
+                    Some(c) if c.is_ascii_digit() => {
+                        let (last, end) = number_at(after + 1);
+                        (first, last?, end)
+                    }
+                    // `x-` leaves out the last word
+                    _ => (first, last_word.checked_sub(1)?, after + 1),
+                },
+                _ => (first, first, after),
+            }
+        }
+    };
+    // `*` and `x*` may select nothing, anything else must name words that exist
+    let may_be_empty = matches!(chars.get(end - 1), Some('*'));
+    if last > last_word || (first > last_word && !may_be_empty) || (first > last && !may_be_empty) {
+        return None;
+    }
+    Some((first, last, end))
+}
+
+// The words of a history entry, quoted words kept whole and operators as words of their own
+fn split_words(line: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                word.push(c);
+                word.extend(chars.next());
+            }
+            (_, Some(open)) => {
+                word.push(c);
+                if c == open {
+                    quote = None;
+                }
+            }
+            ('\'' | '"', None) => {
+                word.push(c);
+                quote = Some(c);
+            }
+            (_, None) if c.is_whitespace() || matches!(c, ';' | '|' | '&' | '<' | '>') => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                if !c.is_whitespace() {
+                    // Doubled operators such as `&&` and `>>` are one word
+                    match words.last_mut() {
+                        Some(last) if last.len() == 1 && last.starts_with(c) && matches!(c, '|' | '&' | '>') => last.push(c),
+                        _ => words.push(String::from(c)),
+                    }
+                }
+            }
+            _ => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+// `:h` removes the last component of a path
+fn head(text: &str) -> String {
+    match text.rfind('/') {
+        Some(0) => String::from("/"),
+        Some(slash) => String::from(&text[..slash]),
+        None => String::from(text),
+    }
+}
+
+// `:t` keeps only the last component of a path
+fn tail(text: &str) -> String {
+    match text.rfind('/') {
+        Some(slash) => String::from(&text[slash + 1..]),
+        None => String::from(text),
+    }
+}
+
+// `:r` removes the suffix of the last component of a path
+fn root(text: &str) -> String {
+    let name_start = text.rfind('/').map(|slash| slash + 1).unwrap_or(0);
+    match text[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => String::from(&text[..name_start + dot]),
+        _ => String::from(text),
+    }
+}
+
+// A `:s/old/new/` modifier, or every occurrence for `:gs/old/new/`
+struct Substitution {
+    old: String,
+    new: String,
+    global: bool,
+}
+
+impl Substitution {
+    fn apply(&self, text: &str) -> Result<String, String> {
+        if !text.contains(self.old.as_str()) {
+            return Err(format!("{}: substitution failed", self.old));
+        }
+        Ok(if self.global {
+            text.replace(self.old.as_str(), self.new.as_str())
+        } else {
+            text.replacen(self.old.as_str(), self.new.as_str(), 1)
+        })
+    }
+}
+
+// Parses a substitution starting at its `s` or `g`, or at the first `^` of a quick
+// substitution, into the substitution and where it ends. The delimiter is the character
+// after the `s`, the last one being optional at the end of the line. A `&` in the new
+// text stands for the old text.
+fn parse_substitution(chars: &[char], start: usize) -> Result<(Substitution, usize), String> {
+    let (global, delimiter_index) = match (chars[start], chars.get(start + 1)) {
+        ('^', _) => (false, start),
+        ('g', Some('s')) => (true, start + 2),
+        ('s', _) => (false, start + 1),
+        _ => return Err(String::from("unrecognized history modifier")),
+    };
+    let delimiter = match chars.get(delimiter_index) {
+        Some(delimiter) if !delimiter.is_whitespace() => *delimiter,
+        _ => return Err(String::from("missing substitution delimiter")),
+    };
+    let (old, after_old) = read_until(chars, delimiter_index + 1, delimiter);
+    let (new, end) = read_until(chars, after_old + 1, delimiter);
+    if old.is_empty() {
+        return Err(String::from("no previous substitution"));
+    }
+    let new = new.replace('&', old.as_str());
+    Ok((Substitution { old, new, global }, (end + 1).min(chars.len())))
+}
+
+// Reads up to an unescaped delimiter or the end of the line, a backslash escaping the delimiter
+fn read_until(chars: &[char], start: usize, delimiter: char) -> (String, usize) {
+    let mut text = String::new();
+    let mut index = start;
+    while index < chars.len() && chars[index] != delimiter {
+        if chars[index] == '\\' && chars.get(index + 1) == Some(&delimiter) {
+            index += 1;
+        }
+        text.push(chars[index]);
+        index += 1;
+    }
+    (text, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HISTORY: [&str; 4] = [
+        "cd /usr/local/src",
+        "grep -n 'two words' notes.txt | sort",
+        "cp archive.tar.gz /tmp/backup/",
+        "echo one two three",
+    ];
+
+    fn expand(line: &str) -> Result<String, String> {
+        let history: Vec<String> = HISTORY.iter().map(|entry| String::from(*entry)).collect();
+        expand_history(line, history.as_slice()).map(|expanded| expanded.line)
+    }
+
+    #[test]
+    fn finds_events() {
+        assert_eq!(expand("!!").unwrap(), "echo one two three");
+        assert_eq!(expand("!1").unwrap(), "cd /usr/local/src");
+        assert_eq!(expand("!-2").unwrap(), "cp archive.tar.gz /tmp/backup/");
+        assert_eq!(expand("!gr").unwrap(), "grep -n 'two words' notes.txt | sort");
+        assert_eq!(expand("!?backup?").unwrap(), "cp archive.tar.gz /tmp/backup/");
+        assert_eq!(expand("x; !! && y").unwrap(), "x; echo one two three && y");
+    }
+
+    #[test]
+    fn reports_missing_events() {
+        assert_eq!(expand("!9").unwrap_err(), "!9: event not found");
+        assert_eq!(expand("!-5").unwrap_err(), "!-5: event not found");
+        assert_eq!(expand("!0").unwrap_err(), "!0: event not found");
+        assert_eq!(expand("!nothing").unwrap_err(), "!nothing: event not found");
+    }
+
+    #[test]
+    fn selects_words() {
+        assert_eq!(expand("!$").unwrap(), "three");
+        assert_eq!(expand("!^").unwrap(), "one");
+        assert_eq!(expand("!*").unwrap(), "one two three");
+        assert_eq!(expand("!:0").unwrap(), "echo");
+        assert_eq!(expand("!:2-3").unwrap(), "two three");
+        assert_eq!(expand("!:-2").unwrap(), "echo one two");
+        assert_eq!(expand("!:1*").unwrap(), "one two three");
+        assert_eq!(expand("!:2-").unwrap(), "two");
+        assert_eq!(expand("!:2-$").unwrap(), "two three");
+        assert_eq!(expand("!1:1").unwrap(), "/usr/local/src");
+    }
+
+    #[test]
+    fn keeps_quoted_words_and_operators_whole() {
+        assert_eq!(expand("!2:2").unwrap(), "'two words'");
+        assert_eq!(expand("!2:4").unwrap(), "|");
+        assert_eq!(expand("!2:$").unwrap(), "sort");
+    }
+
+    #[test]
+    fn rejects_words_that_do_not_exist() {
+        assert_eq!(expand("!:4").unwrap_err(), "!:4: bad word specifier");
+        assert_eq!(expand("!:3-2").unwrap_err(), "!:3-2: bad word specifier");
+        // Only `*` may select nothing
+        assert_eq!(expand("echo !:4*").unwrap(), "echo ");
+    }
+
+    #[test]
+    fn applies_path_modifiers() {
+        assert_eq!(expand("!1:1:h").unwrap(), "/usr/local");
+        assert_eq!(expand("!1:1:t").unwrap(), "src");
+        assert_eq!(expand("!3:1:r").unwrap(), "archive.tar");
+        assert_eq!(expand("!3:1:r:r").unwrap(), "archive");
+        assert_eq!(expand("!1:1:h:h:t").unwrap(), "usr");
+        assert_eq!(head("/usr"), "/");
+        assert_eq!(root(".profile"), ".profile");
+    }
+
+    #[test]
+    fn applies_substitutions() {
+        assert_eq!(expand("!!:s/two/2/").unwrap(), "echo one 2 three");
+        assert_eq!(expand("!!:gs/o/0/").unwrap(), "ech0 0ne tw0 three");
+        assert_eq!(expand("!!:s|one|& and &|").unwrap(), "echo one and one two three");
+        assert_eq!(expand("!!:s/three/3").unwrap(), "echo one two 3");
+        assert_eq!(expand("!!:s/four/4/").unwrap_err(), "four: substitution failed");
+    }
+
+    #[test]
+    fn applies_quick_substitutions() {
+        assert_eq!(expand("^two^2^").unwrap(), "echo one 2 three");
+        assert_eq!(expand("^two^2").unwrap(), "echo one 2 three");
+        assert_eq!(expand("^two^2^ four").unwrap(), "echo one 2 three four");
+        assert!(expand_history("^a^b", &[]).is_err());
+    }
+
+    #[test]
+    fn marks_lines_to_print() {
+        let history = vec![String::from("make test")];
+        let expanded = expand_history("!!:p", history.as_slice()).ok().unwrap();
+        assert!(expanded.print_only && expanded.changed);
+        assert_eq!(expanded.line, "make test");
+    }
+
+    #[test]
+    fn leaves_quoted_and_escaped_references_alone() {
+        assert_eq!(expand("echo '!!' \\!! \"!$\"").unwrap(), "echo '!!' \\!! \"three\"");
+        assert_eq!(expand("echo ! x != !(y)").unwrap(), "echo ! x != !(y)");
+        let expanded = expand_history("echo plain", &[]).ok().unwrap();
+        assert!(!expanded.changed);
+    }
+}
@@ -0,0 +1,291 @@
+use std::io;
+use std::io::IsTerminal;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum JobState {
+    Running,
+    Stopped,
+}
+
+/// A pipeline whose external processes are still alive, either in the background or
+/// stopped with Ctrl-Z.
+pub(crate) struct Job {
+    pub id: usize,
+    // Process group shared by every process of the job, only set with job control
+    pub pgid: Option<i32>,
+    // Processes that have not exited yet
+    pids: Vec<i32>,
+    // The process whose exit status is the status of the job
+    last_pid: Option<i32>,
+    last_status: Option<ExitStatus>,
+    pub command_line: String,
+    pub state: JobState,
+}
+
+pub(crate) enum WaitOutcome {
+    // Status of the last process, if the last stage of the pipeline was a process
+    Exited(Option<ExitStatus>),
+    Stopped(usize),
+}
+
+pub(crate) struct JobTable {
+    jobs: Vec<Job>,
+    job_control: bool,
+    shell_pgid: i32,
+}
+
+impl Job {
+    pub fn new(pgid: Option<i32>, pids: Vec<i32>, last_pid: Option<i32>, command_line: &str) -> Job {
+        Job { id: 0, pgid, pids, last_pid, last_status: None, command_line: String::from(command_line), state: JobState::Running }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pids.is_empty()
+    }
+
+    pub fn last_status(&self) -> Option<ExitStatus> {
+        self.last_status
+    }
+
+    /// Sends a signal to every process of the job, through its process group if it has one.
+    pub fn signal(&self, signal: i32) -> io::Result<()> {
+        let targets: Vec<i32> = match self.pgid {
+            Some(pgid) => vec![-pgid],
+            None => self.pids.clone(),
+        };
+        for target in targets {
+            if unsafe { libc::kill(target, signal) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the status of processes that changed state. Blocking waits until every
+    /// process exited or one of them stopped; returns whether the job is now stopped.
+    fn wait(&mut self, block: bool) -> bool {
+        let options = if block { libc::WUNTRACED } else { libc::WUNTRACED | libc::WCONTINUED | libc::WNOHANG };
+        for pid in self.pids.clone() {
+            loop {
+                let mut status: i32 = 0;
+                let result = unsafe { libc::waitpid(pid, &mut status, options) };
+                if result == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                if result == 0 {
+                    break;
+                }
+                if result == -1 || libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                    // A process we can no longer wait for is as good as gone
+                    self.pids.retain(|alive| *alive != pid);
+                    if result != -1 && Some(pid) == self.last_pid {
+                        self.last_status = Some(ExitStatus::from_raw(status));
+                    }
+                } else if libc::WIFSTOPPED(status) {
+                    self.state = JobState::Stopped;
+                    return true;
+                } else if libc::WIFCONTINUED(status) {
+                    self.state = JobState::Running;
+                }
+                break;
+            }
+        }
+        false
+    }
+}
+
+impl JobTable {
+    pub fn new() -> JobTable {
+        JobTable { jobs: Vec::new(), job_control: false, shell_pgid: 0 }
+    }
+
+    /// An empty table for a subshell, which controls jobs the way the shell does.
+    pub fn for_subshell(&self) -> JobTable {
+        JobTable { jobs: Vec::new(), job_control: self.job_control, shell_pgid: self.shell_pgid }
+    }
+
+    /// Turns on job control when the shell is attached to a terminal: the shell moves into
+    /// its own process group, takes the terminal and ignores the job control signals so
+    /// Ctrl-Z and Ctrl-C only reach the foreground job. Ctrl-C while the shell runs a
+    /// builtin is only noted, for loops to stop at.
+    pub fn enable_job_control(&mut self) {
+        if !io::stdin().is_terminal() {
+            return;
+        }
+        unsafe {
+            for signal in [libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = note_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // Calls the shell was in go on once the handler ran
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+            // Fails harmlessly when the shell already leads its own session
+            libc::setpgid(0, 0);
+            self.shell_pgid = libc::getpgrp();
+            libc::tcsetpgrp(libc::STDIN_FILENO, self.shell_pgid);
+        }
+        self.job_control = true;
+    }
+
+    pub fn is_job_control(&self) -> bool {
+        self.job_control
+    }
+
+    /// Adds a job under the next free number, keeping the number it already had if it
+    /// is being put back (e.g. stopped again after `fg`).
+    pub fn add(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        }
+        let id = job.id;
+        let position = self.jobs.iter().position(|other| other.id > id).unwrap_or(self.jobs.len());
+        self.jobs.insert(position, job);
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        let position = self.jobs.iter().position(|job| job.id == id)?;
+        Some(self.jobs.remove(position))
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    pub fn jobs(&self) -> &Vec<Job> {
+        &self.jobs
+    }
+
+    /// The job `fg` and `bg` act on without an argument, the most recently added one.
+    pub fn current_id(&self) -> Option<usize> {
+        self.jobs.iter().map(|job| job.id).max()
+    }
+
+    /// Resolves `%n`, `%%` or `%+` to a job number.
+    pub fn parse_job_spec(&self, spec: &str) -> Option<usize> {
+        let spec = spec.strip_prefix('%')?;
+        if spec == "%" || spec == "+" {
+            return self.current_id();
+        }
+        let id = spec.parse::<usize>().ok()?;
+        self.jobs.iter().find(|job| job.id == id).map(|job| job.id)
+    }
+
+    /// Runs a job in the foreground, handing it the terminal until every process exited
+    /// or the job was stopped, in which case it is put in the table.
+    pub fn wait_foreground(&mut self, mut job: Job) -> WaitOutcome {
+        self.give_terminal(job.pgid);
+        let stopped = job.wait(true);
+        self.give_terminal(None);
+        if stopped {
+            return WaitOutcome::Stopped(self.add(job));
+        }
+        WaitOutcome::Exited(job.last_status())
+    }
+
+    /// Checks every job without blocking, removing and returning the ones that finished.
+    pub fn reap(&mut self) -> Vec<Job> {
+        for job in self.jobs.iter_mut() {
+            job.wait(false);
+        }
+        let (finished, alive): (Vec<Job>, Vec<Job>) = self.jobs.drain(..).partition(|job| job.is_finished());
+        self.jobs = alive;
+        finished
+    }
+
+    /// Waits for a running job to finish without taking the terminal, returning it once
+    /// it is done. A job that stops is left in the table, and Ctrl-C gives up waiting with
+    /// an `Interrupted` error, leaving the job running.
+    pub fn wait_background(&mut self, id: usize) -> io::Result<Option<Job>> {
+        loop {
+            let job = match self.get_mut(id) {
+                Some(job) => job,
+                None => return Ok(None),
+            };
+            // Polled, as Ctrl-C only sets a flag and a blocking wait would go on after it
+            if job.state == JobState::Stopped || job.wait(false) {
+                return Ok(None);
+            }
+            if job.is_finished() {
+                return Ok(self.remove(id));
+            }
+            if take_interrupt() {
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Hands the terminal to a process group, passing None gives it back to the shell.
+    pub fn give_terminal(&self, pgid: Option<i32>) {
+        if self.job_control {
+            unsafe {
+                libc::tcsetpgrp(libc::STDIN_FILENO, pgid.unwrap_or(self.shell_pgid));
+            }
+        }
+    }
+}
+
+/// How a finished job is described in notices, e.g. `Done` or `Exit 2`.
+pub(crate) fn describe_status(status: Option<ExitStatus>) -> String {
+    match status {
+        Some(status) if status.success() => String::from("Done"),
+        Some(status) => match (status.code(), status.signal()) {
+            (Some(code), _) => format!("Exit {}", code),
+            (_, Some(signal)) => format!("Killed by signal {}", signal),
+            _ => String::from("Done"),
+        },
+        None => String::from("Done"),
+    }
+}
+
+// Set by Ctrl-C while the shell has the terminal, until a loop checks it
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn note_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether Ctrl-C was pressed while the shell itself was running since the last check.
+pub(crate) fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+/// Restores the default handling of the signals the shell ignores or handles, run in a
+/// child before it executes its program.
+pub(crate) fn reset_signals() {
+    unsafe {
+        for signal in [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+}
+
+/// Parses a signal given as a number or a name such as `KILL` or `SIGKILL`.
+pub(crate) fn parse_signal(signal: &str) -> Option<i32> {
+    if let Ok(number) = signal.parse::<i32>() {
+        return Some(number);
+    }
+    let name = signal.to_uppercase();
+    match name.strip_prefix("SIG").unwrap_or(name.as_str()) {
+        "HUP" => Some(libc::SIGHUP),
+        "INT" => Some(libc::SIGINT),
+        "QUIT" => Some(libc::SIGQUIT),
+        "KILL" => Some(libc::SIGKILL),
+        "USR1" => Some(libc::SIGUSR1),
+        "USR2" => Some(libc::SIGUSR2),
+        "TERM" => Some(libc::SIGTERM),
+        "CONT" => Some(libc::SIGCONT),
+        "STOP" => Some(libc::SIGSTOP),
+        "TSTP" => Some(libc::SIGTSTP),
+        _ => None,
+    }
+}
@@ -0,0 +1,515 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+#[derive(Clone)]
+pub(crate) enum Token {
+    Word(Word),
+    Pipe,
+    Redirect(RedirectKind),
+    // A & running the pipeline before it in the background
+    Background,
+    // ;
+    Semicolon,
+    // &&
+    And,
+    // ||
+    Or,
+    // ( opening a subshell
+    OpenParen,
+    // ) closing a subshell
+    CloseParen,
+    // ;; ending an item of a case
+    DoubleSemicolon,
+    // A line break inside input that spans several lines, separating commands like `;`
+    Newline,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word.to_unexpanded_string()),
+            Token::Pipe => write!(f, "|"),
+            Token::Redirect(kind) => write!(f, "{}", kind.symbol()),
+            Token::Background => write!(f, "&"),
+            Token::Semicolon => write!(f, ";"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::OpenParen => write!(f, "("),
+            Token::CloseParen => write!(f, ")"),
+            Token::DoubleSemicolon => write!(f, ";;"),
+            Token::Newline => writeln!(f),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Quoting {
+    // Outside of any quotes, expanded and split into fields
+    Unquoted,
+    // Inside double quotes, expanded but kept as a single field
+    Double,
+    // Single quoted or escaped, taken as it is
+    Literal,
+}
+
+#[derive(Clone)]
+pub(crate) struct WordPart {
+    pub text: String,
+    pub quoting: Quoting,
+}
+
+/// A word of a command line with its quotes removed, remembering how each part of it
+/// was quoted so expansion can treat them differently.
+#[derive(Clone, Default)]
+pub(crate) struct Word {
+    pub parts: Vec<WordPart>,
+}
+
+impl Word {
+    // Makes sure the word ends in a part with the given quoting, so `""` still makes a word
+    fn start(&mut self, quoting: Quoting) -> &mut String {
+        if self.parts.last().map(|part| part.quoting != quoting).unwrap_or(true) {
+            self.parts.push(WordPart { text: String::new(), quoting });
+        }
+        &mut self.parts.last_mut().unwrap().text
+    }
+
+    fn push(&mut self, c: char, quoting: Quoting) {
+        self.start(quoting).push(c);
+    }
+
+    /// The text of a word written without any quotes, escapes or expansions, such as the
+    /// name of an alias.
+    pub fn as_plain(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [part] if part.quoting == Quoting::Unquoted && !part.text.contains('$') => Some(part.text.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The word with its quotes removed but nothing expanded.
+    pub fn to_unexpanded_string(&self) -> String {
+        self.parts.iter().map(|part| part.text.as_str()).collect()
+    }
+
+    /// The word written out with its quotes, so that tokenizing it gives back the same word.
+    pub fn to_source(&self) -> String {
+        let mut source = String::new();
+        for part in &self.parts {
+            match part.quoting {
+                Quoting::Unquoted => source.push_str(part.text.as_str()),
+                // Only an expansion can hold a `"`, and it is kept whole when read back
+                Quoting::Double => source.push_str(format!("\"{}\"", part.text).as_str()),
+                Quoting::Literal => source.push_str(quote(part.text.as_str()).as_str()),
+            }
+        }
+        source
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum RedirectKind {
+    // <
+    Input,
+    // >
+    Output,
+    // >>
+    Append,
+    // 2>
+    Error,
+    // 2>>
+    ErrorAppend,
+    // &>
+    OutputAndError,
+    // 2>&1
+    ErrorToOutput,
+}
+
+impl RedirectKind {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            RedirectKind::Input => "<",
+            RedirectKind::Output => ">",
+            RedirectKind::Append => ">>",
+            RedirectKind::Error => "2>",
+            RedirectKind::ErrorAppend => "2>>",
+            RedirectKind::OutputAndError => "&>",
+            RedirectKind::ErrorToOutput => "2>&1",
+        }
+    }
+}
+
+pub(crate) enum LexErrorKind {
+    UnterminatedSingleQuote,
+    UnterminatedDoubleQuote,
+    UnterminatedBrace,
+    UnterminatedSubstitution,
+    TrailingEscape,
+}
+
+pub(crate) struct LexError {
+    pub kind: LexErrorKind,
+    // Byte offset of the opening quote or brace, or of a dangling backslash
+    pub position: usize,
+}
+
+impl LexError {
+    /// Formats the error with the offending line and a caret under the position.
+    pub fn report(&self, line: &str) -> String {
+        let column = line[..self.position].chars().count();
+        format!("{}\n  {}\n  {}^", self, line.trim_end(), " ".repeat(column))
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let column = self.position + 1;
+        match self.kind {
+            LexErrorKind::UnterminatedSingleQuote => write!(f, "Unterminated single quote starting at column {}", column),
+            LexErrorKind::UnterminatedDoubleQuote => write!(f, "Unterminated double quote starting at column {}", column),
+            LexErrorKind::UnterminatedBrace => write!(f, "Unterminated \"${{\" starting at column {}", column),
+            LexErrorKind::UnterminatedSubstitution => write!(f, "Unterminated command substitution starting at column {}", column),
+            LexErrorKind::TrailingEscape => write!(f, "Nothing to escape after the backslash at column {}", column),
+        }
+    }
+}
+
+/// Splits a command line into words and the pipe, redirection, background, list,
+/// subshell and case operators, a line break being an operator of its own.
+/// Words are separated by any amount of whitespace or an unquoted operator, single quotes
+/// keep everything literally, double quotes keep whitespace but still allow `\"`, `\\`
+/// and `\$` escapes, and a backslash outside quotes escapes any character. A `${...}`
+/// or `$(...)` is kept whole, whatever it contains, and a backquoted command is kept as
+/// the `$(...)` it stands for.
+pub(crate) fn tokenize(line: &str) -> Result<Vec<Token>, LexError> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    let mut current: Option<Word> = None;
+
+    while let Some((position, c)) = chars.next() {
+        let operator = match c {
+            '|' if chars.next_if(|(_, c)| *c == '|').is_some() => Some(Token::Or),
+            '|' => Some(Token::Pipe),
+            ';' if chars.next_if(|(_, c)| *c == ';').is_some() => Some(Token::DoubleSemicolon),
+            ';' => Some(Token::Semicolon),
+            '\n' => Some(Token::Newline),
+            '(' => Some(Token::OpenParen),
+            ')' => Some(Token::CloseParen),
+            '<' => Some(Token::Redirect(RedirectKind::Input)),
+            '>' if chars.next_if(|(_, c)| *c == '>').is_some() => Some(Token::Redirect(RedirectKind::Append)),
+            '>' => Some(Token::Redirect(RedirectKind::Output)),
+            '&' if chars.next_if(|(_, c)| *c == '&').is_some() => Some(Token::And),
+            '&' if chars.next_if(|(_, c)| *c == '>').is_some() => Some(Token::Redirect(RedirectKind::OutputAndError)),
+            '&' => Some(Token::Background),
+            // Only a 2 at the start of a word names stderr, `a2>b` writes a2 to b
+            '2' if current.is_none() && chars.next_if(|(_, c)| *c == '>').is_some() => {
+                let mut ahead = chars.clone();
+                if chars.next_if(|(_, c)| *c == '>').is_some() {
+                    Some(Token::Redirect(RedirectKind::ErrorAppend))
+                } else if matches!((ahead.next(), ahead.next()), (Some((_, '&')), Some((_, '1')))) {
+                    chars = ahead;
+                    Some(Token::Redirect(RedirectKind::ErrorToOutput))
+                } else {
+                    Some(Token::Redirect(RedirectKind::Error))
+                }
+            }
+            _ => None,
+        };
+        if c.is_whitespace() || operator.is_some() {
+            if let Some(word) = current.take() {
+                tokens.push(Token::Word(word));
+            }
+            tokens.extend(operator);
+            continue;
+        }
+        // An escaped newline only joins lines, it does not start a word
+        if c == '\\' && matches!(chars.peek(), Some((_, '\n'))) {
+            chars.next();
+            continue;
+        }
+        let word = current.get_or_insert_with(Word::default);
+        match c {
+            '\'' => {
+                word.start(Quoting::Literal);
+                loop {
+                    match chars.next() {
+                        Some((_, '\'')) => break,
+                        Some((_, c)) => word.push(c, Quoting::Literal),
+                        None => return Err(LexError { kind: LexErrorKind::UnterminatedSingleQuote, position }),
+                    }
+                }
+            }
+            '"' => {
+                word.start(Quoting::Double);
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.peek() {
+                            Some((_, escaped @ ('"' | '\\' | '$' | '`'))) => {
+                                word.push(*escaped, Quoting::Literal);
+                                chars.next();
+                            }
+                            Some((_, '\n')) => {
+                                chars.next();
+                            }
+                            _ => word.push('\\', Quoting::Double),
+                        },
+                        Some((brace_position, '$')) if chars.peek().map(|(_, c)| *c == '{').unwrap_or(false) => {
+                            read_braces(&mut chars, word.start(Quoting::Double), brace_position)?;
+                        }
+                        Some((dollar_position, '$')) if chars.peek().map(|(_, c)| *c == '(').unwrap_or(false) => {
+                            read_substitution(line, &mut chars, word.start(Quoting::Double), dollar_position)?;
+                        }
+                        Some((quote_position, '`')) => read_backquotes(&mut chars, word.start(Quoting::Double), quote_position)?,
+                        Some((_, c)) => word.push(c, Quoting::Double),
+                        None => return Err(LexError { kind: LexErrorKind::UnterminatedDoubleQuote, position }),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some((_, escaped)) => word.push(escaped, Quoting::Literal),
+                None => return Err(LexError { kind: LexErrorKind::TrailingEscape, position }),
+            },
+            '$' if chars.peek().map(|(_, c)| *c == '{').unwrap_or(false) => {
+                read_braces(&mut chars, word.start(Quoting::Unquoted), position)?;
+            }
+            '$' if chars.peek().map(|(_, c)| *c == '(').unwrap_or(false) => {
+                read_substitution(line, &mut chars, word.start(Quoting::Unquoted), position)?;
+            }
+            '`' => read_backquotes(&mut chars, word.start(Quoting::Unquoted), position)?,
+            _ => word.push(c, Quoting::Unquoted),
+        }
+    }
+    if let Some(word) = current.take() {
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+// Copies a `${...}` into the text as it is, up to the brace closing it
+fn read_braces(chars: &mut Peekable<CharIndices>, text: &mut String, position: usize) -> Result<(), LexError> {
+    text.push('$');
+    let mut depth = 0;
+    for (_, c) in chars.by_ref() {
+        text.push(c);
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => return Ok(()),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    Err(LexError { kind: LexErrorKind::UnterminatedBrace, position })
+}
+
+// Copies a `$(...)` into the text as it is, the `$` being at `position` of the line
+fn read_substitution(line: &str, chars: &mut Peekable<CharIndices>, text: &mut String,
+                     position: usize) -> Result<(), LexError> {
+    let start = position + 2;
+    let end = start + matching_paren(&line[start..])
+        .ok_or(LexError { kind: LexErrorKind::UnterminatedSubstitution, position })?;
+    text.push_str(&line[position..=end]);
+    while chars.next_if(|(index, _)| *index <= end).is_some() {}
+    Ok(())
+}
+
+// Reads a backquoted command up to the closing backquote into the text as a `$(...)`.
+// Inside it a backslash only escapes a backquote, `$` or another backslash, so nested
+// commands are written with escaped backquotes.
+fn read_backquotes(chars: &mut Peekable<CharIndices>, text: &mut String, position: usize) -> Result<(), LexError> {
+    text.push_str("$(");
+    loop {
+        match chars.next() {
+            Some((_, '`')) => break,
+            Some((_, '\\')) if matches!(chars.peek(), Some((_, '`' | '$' | '\\'))) => text.push(chars.next().unwrap().1),
+            Some((_, c)) => text.push(c),
+            None => return Err(LexError { kind: LexErrorKind::UnterminatedSubstitution, position }),
+        }
+    }
+    text.push(')');
+    Ok(())
+}
+
+/// Finds the `)` closing a command substitution in the text after its `$(`, skipping
+/// over quotes, escapes and nested parentheses.
+pub(crate) fn matching_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = text.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\'' => {
+                chars.find(|(_, c)| *c == '\'')?;
+            }
+            '"' | '`' => loop {
+                match chars.next()? {
+                    (_, '\\') => {
+                        chars.next();
+                    }
+                    (_, closing) if closing == c => break,
+                    _ => {}
+                }
+            },
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(index),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Writes tokens back out as a line, or several when there are line breaks among them,
+/// that tokenizes to the same tokens.
+pub(crate) fn to_source(tokens: &[Token]) -> String {
+    let mut source = String::new();
+    for token in tokens {
+        if let Token::Newline = token {
+            source.push('\n');
+            continue;
+        }
+        let separator = matches!(token, Token::Semicolon | Token::DoubleSemicolon);
+        if !source.is_empty() && !source.ends_with('\n') && !separator {
+            source.push(' ');
+        }
+        match token {
+            Token::Word(word) => source.push_str(word.to_source().as_str()),
+            _ => source.push_str(token.to_string().as_str()),
+        }
+    }
+    source
+}
+
+/// Quotes a word so that tokenizing the result gives back the same word.
+pub(crate) fn quote(word: &str) -> String {
+    let plain = !word.is_empty() && word.chars()
+        .all(|c| !c.is_whitespace() && !matches!(c, '\'' | '"' | '`' | '\\' | '|' | ';' | '(' | ')' | '<' | '>' | '&' | '$' | '*' | '?' | '['));
+    // A lone brace would open or close a group, and a leading tilde expand to a home
+    if plain && word != "{" && word != "}" && !word.starts_with('~') {
+        return String::from(word);
+    }
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each token as it is displayed, words with their quotes removed
+    fn tokens(line: &str) -> Vec<String> {
+        match tokenize(line) {
+            Ok(tokens) => tokens.iter().map(|token| token.to_string()).collect(),
+            Err(error) => panic!("failed to tokenize {:?}: {}", line, error),
+        }
+    }
+
+    fn error_position(line: &str) -> usize {
+        match tokenize(line) {
+            Ok(_) => panic!("tokenized {:?} without an error", line),
+            Err(error) => error.position,
+        }
+    }
+
+    #[test]
+    fn splits_words_on_whitespace() {
+        assert_eq!(tokens("  echo   one\ttwo "), ["echo", "one", "two"]);
+        assert!(tokens("   ").is_empty());
+    }
+
+    #[test]
+    fn single_quotes_keep_everything() {
+        assert_eq!(tokens(r#"echo 'a  b' '$HOME' 'x\y' '"'"#), ["echo", "a  b", "$HOME", r"x\y", "\""]);
+    }
+
+    #[test]
+    fn double_quotes_keep_whitespace_and_allow_escapes() {
+        assert_eq!(tokens(r#"echo "a  b" "q\"q" "b\\s" "\$x" "\n""#), ["echo", "a  b", "q\"q", r"b\s", "$x", r"\n"]);
+    }
+
+    #[test]
+    fn adjacent_quoted_parts_make_one_word() {
+        let words = tokenize(r#"a'b'"c"\d"#).ok().unwrap();
+        match words.as_slice() {
+            [Token::Word(word)] => {
+                let quoting: Vec<Quoting> = word.parts.iter().map(|part| part.quoting).collect();
+                assert!(quoting == [Quoting::Unquoted, Quoting::Literal, Quoting::Double, Quoting::Literal]);
+                assert_eq!(word.to_unexpanded_string(), "abcd");
+            }
+            _ => panic!("expected a single word"),
+        }
+    }
+
+    #[test]
+    fn empty_quotes_make_a_word() {
+        assert_eq!(tokens("echo '' \"\""), ["echo", "", ""]);
+    }
+
+    #[test]
+    fn backslash_escapes_operators_and_whitespace() {
+        assert_eq!(tokens(r"echo a\ b \| \;"), ["echo", "a b", "|", ";"]);
+    }
+
+    #[test]
+    fn splits_operators_without_whitespace() {
+        assert_eq!(tokens("a|b;c&&d||e&"), ["a", "|", "b", ";", "c", "&&", "d", "||", "e", "&"]);
+        assert_eq!(tokens("(a);;b"), ["(", "a", ")", ";;", "b"]);
+        assert_eq!(tokens("a\nb"), ["a", "\n", "b"]);
+    }
+
+    #[test]
+    fn reads_redirections() {
+        assert_eq!(tokens("a<in>out>>log 2>err 2>>errlog &>all 2>&1"),
+                   ["a", "<", "in", ">", "out", ">>", "log", "2>", "err", "2>>", "errlog", "&>", "all", "2>&1"]);
+    }
+
+    #[test]
+    fn keeps_expansions_whole() {
+        assert_eq!(tokens("echo ${a b} $(echo a; echo b) x"), ["echo", "${a b}", "$(echo a; echo b)", "x"]);
+        assert_eq!(tokens("echo $(echo ')') \"$(echo \"a b\")\""), ["echo", "$(echo ')')", "$(echo \"a b\")"]);
+    }
+
+    #[test]
+    fn reads_backquotes_as_substitutions() {
+        assert_eq!(tokens("echo `echo a`"), ["echo", "$(echo a)"]);
+    }
+
+    #[test]
+    fn reports_where_an_unterminated_quote_starts() {
+        assert_eq!(error_position("echo 'abc"), 5);
+        assert_eq!(error_position("echo a\"bc"), 6);
+        assert_eq!(error_position("echo ${abc"), 5);
+        assert_eq!(error_position("echo $(abc"), 5);
+        assert_eq!(error_position("echo \\"), 5);
+    }
+
+    #[test]
+    fn source_tokenizes_to_the_same_tokens() {
+        for line in ["echo 'a b' \"$x y\" c\\ d", "a && b || c; d &", "x > 'out file' 2>&1", "echo '{' } \\$ '*'"] {
+            let source = to_source(&tokenize(line).ok().unwrap());
+            assert_eq!(tokens(source.as_str()), tokens(line), "{:?} was written back as {:?}", line, source);
+        }
+        // Quotes that keep a tilde from expanding are kept too
+        assert_eq!(to_source(&tokenize("echo '~' '~/x' ~/y a~").ok().unwrap()), "echo '~' '~/x' ~/y a~");
+    }
+
+    #[test]
+    fn quotes_only_when_needed() {
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("{"), "'{'");
+        assert_eq!(quote("~/x"), "'~/x'");
+        assert_eq!(quote("a~"), "a~");
+    }
+
+    #[test]
+    fn finds_the_matching_paren() {
+        assert_eq!(matching_paren("a (b) c) d"), Some(7));
+        assert_eq!(matching_paren("')' \")\" \\) x)"), Some(12));
+        assert_eq!(matching_paren("(a"), None);
+    }
+}
@@ -0,0 +1,642 @@
+use crate::lexer;
+use crate::lexer::{Quoting, RedirectKind, Token, Word};
+use crate::pipeline::{ParseError, Pipeline, Redirect, Stage};
+use crate::status::Status;
+use crate::variables::is_valid_name;
+
+/// How a pipeline of a list depends on the one that ran before it.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Connector {
+    // The first pipeline, or one after `;` or `&`, which always runs
+    Always,
+    // After `&&`, runs only when the last pipeline that ran succeeded
+    And,
+    // After `||`, runs only when the last pipeline that ran failed
+    Or,
+}
+
+impl Connector {
+    /// Whether a pipeline joined by the connector runs, given the status of the last
+    /// pipeline that ran. Skipped pipelines leave the status alone, so in
+    /// `false && a || b` it is `b` that runs.
+    pub fn should_run(&self, last_status: Status) -> bool {
+        match self {
+            Connector::Always => true,
+            Connector::And => last_status.is_success(),
+            Connector::Or => !last_status.is_success(),
+        }
+    }
+}
+
+/// Pipelines joined by `;`, `&`, `&&` and `||`, as in `cd build && make || echo failed`.
+#[derive(Clone)]
+pub(crate) struct CommandList {
+    pub items: Vec<(Connector, Pipeline)>,
+}
+
+/// A compound command, used as a single stage of a pipeline.
+#[derive(Clone)]
+pub(crate) enum Compound {
+    // ( list ), run in a copy of the shell so nothing it changes is kept
+    Subshell(CommandList),
+    // { list; }, run in the shell itself
+    Group(CommandList),
+    // if list; then list; [elif list; then list;]... [else list;] fi
+    If {
+        // Each condition with the list run when it is the first one that succeeds
+        branches: Vec<(CommandList, CommandList)>,
+        otherwise: Option<CommandList>,
+    },
+    // while list; do list; done, or until when the condition is to fail
+    While {
+        until: bool,
+        condition: CommandList,
+        body: CommandList,
+    },
+    // for name [in word...]; do list; done
+    For {
+        name: String,
+        // None without `in`, when the loop goes over the positional parameters
+        words: Option<Vec<Word>>,
+        body: CommandList,
+    },
+    // case word in [(] pattern [| pattern]...) list;; ... esac
+    Case {
+        word: Word,
+        items: Vec<(Vec<Word>, CommandList)>,
+    },
+    // name() compound, which defines the function rather than running it
+    Function {
+        name: String,
+        body: Box<Compound>,
+        // The definition written out, for listing and saving it
+        text: String,
+    },
+}
+
+impl Compound {
+    /// The lists of the compound command, in the order they are written.
+    pub fn lists(&self) -> Vec<&CommandList> {
+        match self {
+            Compound::Subshell(list) | Compound::Group(list) => vec![list],
+            Compound::If { branches, otherwise } => branches.iter()
+                .flat_map(|(condition, body)| [condition, body])
+                .chain(otherwise)
+                .collect(),
+            Compound::While { condition, body, .. } => vec![condition, body],
+            Compound::For { body, .. } => vec![body],
+            Compound::Case { items, .. } => items.iter().map(|(_, body)| body).collect(),
+            Compound::Function { body, .. } => body.lists(),
+        }
+    }
+}
+
+/// Parses the tokens of a line into a list of pipelines. A `;`, `&` or line break may
+/// end a list, a `&&`, `||` or `|` may not. A stage of a pipeline is either a simple
+/// command or a compound command followed by its redirections: `( list )`, `{ list; }`,
+/// `if`, `while`, `until`, `for` or `case`, or the definition of a function as
+/// `name() compound`. Reserved words such as `{`, `if` and `done`
+/// are only special as the first word of a command. A line without any tokens gives an
+/// empty list.
+pub(crate) fn parse(tokens: Vec<Token>) -> Result<CommandList, ParseError> {
+    let mut parser = Parser { tokens, position: 0 };
+    let list = parser.parse_list(&[])?;
+    match parser.peek() {
+        Some(token) => Err(ParseError::Unexpected(token.to_string())),
+        None => Ok(list),
+    }
+}
+
+// Words that end a list inside a compound command, and can not start a command
+const CLOSING_WORDS: [&str; 9] = ["}", "then", "elif", "else", "fi", "do", "done", "esac", "in"];
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // Whether the next token is a word written as the given text, such as a `}`
+    fn peek_is_word(&self, text: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.as_plain() == Some(text))
+    }
+
+    fn skip_newlines(&mut self) {
+        while matches!(self.peek(), Some(Token::Newline)) {
+            self.position += 1;
+        }
+    }
+
+    // Parses pipelines up to the end of the tokens, a `)`, a `;;` or one of the words
+    // closing the compound command the list is in
+    fn parse_list(&mut self, closing: &[&str]) -> Result<CommandList, ParseError> {
+        let mut items: Vec<(Connector, Pipeline)> = Vec::new();
+        let mut connector = Connector::Always;
+        let mut operator = "";
+        loop {
+            self.skip_newlines();
+            let at_end = matches!(self.peek(), None | Some(Token::CloseParen | Token::DoubleSemicolon))
+                || closing.iter().any(|word| self.peek_is_word(word));
+            if at_end {
+                if connector != Connector::Always {
+                    return Err(match self.peek() {
+                        Some(token) => ParseError::Unexpected(token.to_string()),
+                        None => ParseError::MissingCommandAfter(operator),
+                    });
+                }
+                break;
+            }
+            let mut pipeline = self.parse_pipeline()?;
+            let (next_connector, next_operator, background) = match self.peek() {
+                Some(Token::Semicolon) => (Connector::Always, ";", false),
+                Some(Token::Newline) => (Connector::Always, "\n", false),
+                Some(Token::Background) => (Connector::Always, "&", true),
+                Some(Token::And) => (Connector::And, "&&", false),
+                Some(Token::Or) => (Connector::Or, "||", false),
+                _ => {
+                    items.push((connector, pipeline));
+                    break;
+                }
+            };
+            self.position += 1;
+            pipeline.background = background;
+            items.push((connector, pipeline));
+            connector = next_connector;
+            operator = next_operator;
+        }
+        Ok(CommandList { items })
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let start = self.position;
+        let mut stages: Vec<Stage<Word>> = vec![self.parse_stage(true)?];
+        while matches!(self.peek(), Some(Token::Pipe)) {
+            self.position += 1;
+            self.skip_newlines();
+            if self.peek().is_none() {
+                return Err(ParseError::MissingCommandAfter("|"));
+            }
+            stages.push(self.parse_stage(false)?);
+        }
+        let text = self.tokens[start..self.position].iter().map(Token::to_string).collect::<Vec<String>>().join(" ");
+        Ok(Pipeline { stages, background: false, text })
+    }
+
+    fn parse_stage(&mut self, first: bool) -> Result<Stage<Word>, ParseError> {
+        let mut stage: Stage<Word> = Stage::new();
+        if let Some(function) = self.parse_function()? {
+            stage.compound = Some(function);
+            return Ok(stage);
+        }
+        stage.compound = self.parse_compound()?;
+
+        loop {
+            match self.peek() {
+                Some(Token::Word(_)) if stage.compound.is_some() => {
+                    return Err(ParseError::Unexpected(self.peek().unwrap().to_string()));
+                }
+                Some(Token::Word(word)) if stage.words.is_empty() && CLOSING_WORDS.contains(&word.as_plain().unwrap_or_default()) => {
+                    return Err(ParseError::Unexpected(word.to_unexpanded_string()));
+                }
+                Some(Token::Word(word)) => {
+                    match split_assignment(word) {
+                        Some(assignment) if stage.words.is_empty() => stage.assignments.push(assignment),
+                        _ => stage.words.push(word.clone()),
+                    }
+                    self.position += 1;
+                }
+                Some(Token::Redirect(RedirectKind::ErrorToOutput)) => {
+                    stage.redirects.push(Redirect { kind: RedirectKind::ErrorToOutput, target: None });
+                    self.position += 1;
+                }
+                Some(Token::Redirect(kind)) => {
+                    let kind = *kind;
+                    self.position += 1;
+                    match self.next() {
+                        Some(Token::Word(target)) => stage.redirects.push(Redirect { kind, target: Some(target) }),
+                        _ => return Err(ParseError::MissingRedirectTarget),
+                    }
+                }
+                Some(Token::OpenParen) => return Err(ParseError::Unexpected(String::from("("))),
+                _ => break,
+            }
+        }
+
+        if stage.words.is_empty() && stage.assignments.is_empty() && stage.compound.is_none() {
+            return Err(match self.peek() {
+                Some(Token::Semicolon) if first => ParseError::MissingCommandBefore(";"),
+                Some(Token::Background) if first => ParseError::MissingCommandBefore("&"),
+                Some(Token::And) if first => ParseError::MissingCommandBefore("&&"),
+                Some(Token::Or) if first => ParseError::MissingCommandBefore("||"),
+                _ => ParseError::MissingCommand,
+            });
+        }
+        Ok(stage)
+    }
+
+    // Parses the compound command starting at the next token, if one does
+    fn parse_compound(&mut self) -> Result<Option<Compound>, ParseError> {
+        if matches!(self.peek(), Some(Token::OpenParen)) {
+            self.position += 1;
+            let list = self.parse_list(&[])?;
+            if !matches!(self.peek(), Some(Token::CloseParen)) {
+                return Err(self.unexpected_or_unclosed("("));
+            }
+            self.position += 1;
+            if list.items.is_empty() {
+                return Err(ParseError::Unexpected(String::from(")")));
+            }
+            return Ok(Some(Compound::Subshell(list)));
+        }
+        let keyword = match self.peek() {
+            Some(Token::Word(word)) => match word.as_plain() {
+                Some(keyword @ ("{" | "if" | "while" | "until" | "for" | "case")) => String::from(keyword),
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        self.position += 1;
+        let compound = match keyword.as_str() {
+            "{" => Compound::Group(self.parse_body(&["}"], "{")?),
+            "if" => self.parse_if()?,
+            "while" | "until" => {
+                let opening = if keyword == "while" { "while" } else { "until" };
+                let condition = self.parse_body(&["do"], opening)?;
+                let body = self.parse_body(&["done"], opening)?;
+                Compound::While { until: keyword == "until", condition, body }
+            }
+            "for" => self.parse_for()?,
+            _ => self.parse_case()?,
+        };
+        Ok(Some(compound))
+    }
+
+    // Parses the definition of a function starting at the next token, if one does
+    fn parse_function(&mut self) -> Result<Option<Compound>, ParseError> {
+        let name = match self.tokens.get(self.position..self.position + 3) {
+            Some([Token::Word(word), Token::OpenParen, Token::CloseParen]) => match word.as_plain() {
+                Some(name) if is_function_name(name) => String::from(name),
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        self.position += 3;
+        self.skip_newlines();
+        let body_start = self.position;
+        let body = match self.parse_compound()? {
+            Some(body) => body,
+            None => return Err(match self.peek() {
+                Some(token) => ParseError::Unexpected(token.to_string()),
+                None => ParseError::MissingCommandAfter("()"),
+            }),
+        };
+        let text = format!("{}() {}", name, lexer::to_source(&self.tokens[body_start..self.position]));
+        Ok(Some(Compound::Function { name, body: Box::new(body), text }))
+    }
+
+    fn parse_if(&mut self) -> Result<Compound, ParseError> {
+        let mut branches: Vec<(CommandList, CommandList)> = Vec::new();
+        let mut otherwise: Option<CommandList> = None;
+        loop {
+            let condition = self.parse_body(&["then"], "if")?;
+            let body = self.parse_list(&["elif", "else", "fi"])?;
+            let closing = self.expect_word(&["elif", "else", "fi"], "if")?;
+            if body.items.is_empty() {
+                return Err(ParseError::Unexpected(String::from(closing)));
+            }
+            branches.push((condition, body));
+            match closing {
+                "elif" => continue,
+                "else" => otherwise = Some(self.parse_body(&["fi"], "if")?),
+                _ => {}
+            }
+            break;
+        }
+        Ok(Compound::If { branches, otherwise })
+    }
+
+    fn parse_for(&mut self) -> Result<Compound, ParseError> {
+        let name = match self.next() {
+            Some(Token::Word(word)) if word.as_plain().map(is_valid_name).unwrap_or(false) => word.to_unexpanded_string(),
+            Some(token) => return Err(ParseError::Unexpected(token.to_string())),
+            None => return Err(ParseError::Unclosed("for")),
+        };
+        self.skip_newlines();
+        let mut words: Option<Vec<Word>> = None;
+        if self.peek_is_word("in") {
+            self.position += 1;
+            let mut in_words: Vec<Word> = Vec::new();
+            loop {
+                match self.next() {
+                    Some(Token::Word(word)) => in_words.push(word),
+                    Some(Token::Semicolon | Token::Newline) => break,
+                    Some(token) => return Err(ParseError::Unexpected(token.to_string())),
+                    None => return Err(ParseError::Unclosed("for")),
+                }
+            }
+            words = Some(in_words);
+        } else if matches!(self.peek(), Some(Token::Semicolon)) {
+            self.position += 1;
+        }
+        self.skip_newlines();
+        self.expect_word(&["do"], "for")?;
+        let body = self.parse_body(&["done"], "for")?;
+        Ok(Compound::For { name, words, body })
+    }
+
+    fn parse_case(&mut self) -> Result<Compound, ParseError> {
+        let word = match self.next() {
+            Some(Token::Word(word)) => word,
+            Some(token) => return Err(ParseError::Unexpected(token.to_string())),
+            None => return Err(ParseError::Unclosed("case")),
+        };
+        self.skip_newlines();
+        self.expect_word(&["in"], "case")?;
+        let mut items: Vec<(Vec<Word>, CommandList)> = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek_is_word("esac") {
+                self.position += 1;
+                break;
+            }
+            if matches!(self.peek(), Some(Token::OpenParen)) {
+                self.position += 1;
+            }
+            let mut patterns: Vec<Word> = Vec::new();
+            loop {
+                match self.next() {
+                    Some(Token::Word(pattern)) => patterns.push(pattern),
+                    Some(token) => return Err(ParseError::Unexpected(token.to_string())),
+                    None => return Err(ParseError::Unclosed("case")),
+                }
+                match self.next() {
+                    Some(Token::Pipe) => continue,
+                    Some(Token::CloseParen) => break,
+                    Some(token) => return Err(ParseError::Unexpected(token.to_string())),
+                    None => return Err(ParseError::Unclosed("case")),
+                }
+            }
+            let body = self.parse_list(&["esac"])?;
+            items.push((patterns, body));
+            match self.peek() {
+                Some(Token::DoubleSemicolon) => self.position += 1,
+                _ if self.peek_is_word("esac") => {}
+                _ => return Err(self.unexpected_or_unclosed("case")),
+            }
+        }
+        Ok(Compound::Case { word, items })
+    }
+
+    // Parses a list that has to hold at least one command, up to the word closing it
+    fn parse_body(&mut self, closing: &[&'static str], opening: &'static str) -> Result<CommandList, ParseError> {
+        let list = self.parse_list(closing)?;
+        let closed = self.expect_word(closing, opening)?;
+        if list.items.is_empty() {
+            return Err(ParseError::Unexpected(String::from(closed)));
+        }
+        Ok(list)
+    }
+
+    // Takes the next token when it is one of the given words, giving which one it is
+    fn expect_word(&mut self, words: &[&'static str], opening: &'static str) -> Result<&'static str, ParseError> {
+        match words.iter().find(|word| self.peek_is_word(word)) {
+            Some(word) => {
+                self.position += 1;
+                Ok(*word)
+            }
+            None => Err(self.unexpected_or_unclosed(opening)),
+        }
+    }
+
+    // The error for a compound command missing its closing token, which is only unclosed
+    // when the tokens ran out
+    fn unexpected_or_unclosed(&self, opening: &'static str) -> ParseError {
+        match self.peek() {
+            Some(token) => ParseError::Unexpected(token.to_string()),
+            None => ParseError::Unclosed(opening),
+        }
+    }
+}
+
+// Whether a word can name a function: letters, digits, `_` and `-`, as in `git-root`,
+// without being a reserved word
+fn is_function_name(name: &str) -> bool {
+    let reserved = CLOSING_WORDS.contains(&name) || matches!(name, "{" | "if" | "while" | "until" | "for" | "case");
+    !reserved && !name.starts_with('-') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// Splits `NAME=value` into the name and the value, the name and `=` being unquoted
+fn split_assignment(word: &Word) -> Option<(String, Word)> {
+    let first = word.parts.first().filter(|part| part.quoting == Quoting::Unquoted)?;
+    let (name, value) = first.text.split_once('=')?;
+    if !is_valid_name(name) {
+        return None;
+    }
+    let mut value_word = word.clone();
+    value_word.parts[0].text = String::from(value);
+    Some((String::from(name), value_word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The parsed line written out with every list in brackets and every connector kept,
+    // so the tests can tell how it was grouped
+    fn parsed(line: &str) -> String {
+        match parse(lexer::tokenize(line).ok().unwrap()) {
+            Ok(list) => describe_list(&list),
+            Err(error) => panic!("failed to parse {:?}: {}", line, error),
+        }
+    }
+
+    fn error(line: &str) -> ParseError {
+        match parse(lexer::tokenize(line).ok().unwrap()) {
+            Ok(list) => panic!("parsed {:?} as {}", line, describe_list(&list)),
+            Err(error) => error,
+        }
+    }
+
+    fn describe_list(list: &CommandList) -> String {
+        let items: Vec<String> = list.items.iter().map(|(connector, pipeline)| {
+            let connector = match connector {
+                Connector::Always => "",
+                Connector::And => "&& ",
+                Connector::Or => "|| ",
+            };
+            let stages: Vec<String> = pipeline.stages.iter().map(describe_stage).collect();
+            format!("{}{}{}", connector, stages.join(" | "), if pipeline.background { " &" } else { "" })
+        }).collect();
+        format!("[{}]", items.join("; "))
+    }
+
+    fn describe_stage(stage: &Stage<Word>) -> String {
+        let mut parts: Vec<String> = stage.assignments.iter()
+            .map(|(name, value)| format!("{}={}", name, value.to_source()))
+            .collect();
+        parts.extend(stage.compound.iter().map(describe_compound));
+        parts.extend(stage.words.iter().map(Word::to_source));
+        parts.extend(stage.redirects.iter().map(|redirect| match &redirect.target {
+            Some(target) => format!("{}{}", redirect.kind.symbol(), target.to_source()),
+            None => String::from(redirect.kind.symbol()),
+        }));
+        parts.join(" ")
+    }
+
+    fn describe_compound(compound: &Compound) -> String {
+        let words = |words: &[Word]| words.iter().map(Word::to_source).collect::<Vec<String>>().join(" ");
+        match compound {
+            Compound::Subshell(list) => format!("({})", describe_list(list)),
+            Compound::Group(list) => format!("{{{}}}", describe_list(list)),
+            Compound::If { branches, otherwise } => {
+                let mut text: Vec<String> = branches.iter()
+                    .map(|(condition, body)| format!("if {} then {}", describe_list(condition), describe_list(body)))
+                    .collect();
+                text.extend(otherwise.iter().map(|list| format!("else {}", describe_list(list))));
+                text.join(" ")
+            }
+            Compound::While { until, condition, body } => {
+                format!("{} {} do {}", if *until { "until" } else { "while" }, describe_list(condition), describe_list(body))
+            }
+            Compound::For { name, words: Some(in_words), body } => {
+                format!("for {} in {} do {}", name, words(in_words), describe_list(body))
+            }
+            Compound::For { name, words: None, body } => format!("for {} do {}", name, describe_list(body)),
+            Compound::Case { word, items } => {
+                let items: Vec<String> = items.iter()
+                    .map(|(patterns, body)| format!("{}) {}", words(patterns).replace(' ', "|"), describe_list(body)))
+                    .collect();
+                format!("case {} in {}", word.to_source(), items.join(" "))
+            }
+            Compound::Function { name, body, .. } => format!("{}() {}", name, describe_compound(body)),
+        }
+    }
+
+    #[test]
+    fn parses_an_empty_line() {
+        assert_eq!(parsed(""), "[]");
+        assert_eq!(parsed("\n\n"), "[]");
+    }
+
+    #[test]
+    fn parses_connectors() {
+        assert_eq!(parsed("a; b && c || d"), "[a; b; && c; || d]");
+        assert_eq!(parsed("a;"), "[a]");
+        assert_eq!(parsed("a\nb\n"), "[a; b]");
+    }
+
+    #[test]
+    fn parses_background_pipelines() {
+        assert_eq!(parsed("a & b | c &"), "[a &; b | c &]");
+    }
+
+    #[test]
+    fn parses_pipelines_with_assignments_and_redirections() {
+        assert_eq!(parsed("A=1 B='x y' env | grep A > out 2>&1"), "[A=1 B='x y' env | grep A >out 2>&1]");
+        assert_eq!(parsed("echo A=1"), "[echo A=1]");
+        assert_eq!(parsed("a |\nb"), "[a | b]");
+    }
+
+    #[test]
+    fn only_takes_unquoted_names_as_assignments() {
+        let list = parse(lexer::tokenize("'A'=1 B=2 x").ok().unwrap()).ok().unwrap();
+        let stage = &list.items[0].1.stages[0];
+        // Once a word is not an assignment, the rest are arguments
+        assert!(stage.assignments.is_empty());
+        assert_eq!(stage.words.len(), 3);
+    }
+
+    #[test]
+    fn keeps_the_text_of_each_pipeline() {
+        let list = parse(lexer::tokenize("a  'b c' | d; e").ok().unwrap()).ok().unwrap();
+        let texts: Vec<&str> = list.items.iter().map(|(_, pipeline)| pipeline.text.as_str()).collect();
+        assert_eq!(texts, ["a b c | d", "e"]);
+    }
+
+    #[test]
+    fn reports_missing_commands() {
+        assert!(matches!(error("; a"), ParseError::MissingCommandBefore(";")));
+        assert!(matches!(error("&& a"), ParseError::MissingCommandBefore("&&")));
+        assert!(matches!(error("a & && b"), ParseError::MissingCommandBefore("&&")));
+        assert!(matches!(error("a | | b"), ParseError::MissingCommand));
+        assert!(matches!(error("a ;; b"), ParseError::Unexpected(token) if token == ";;"));
+        assert!(matches!(error("a > "), ParseError::MissingRedirectTarget));
+    }
+
+    #[test]
+    fn tells_incomplete_lines_apart() {
+        assert!(error("a &&").is_incomplete());
+        assert!(error("a ||\n").is_incomplete());
+        assert!(error("a |").is_incomplete());
+        assert!(!error("a && ;").is_incomplete());
+    }
+
+    #[test]
+    fn parses_if() {
+        assert_eq!(parsed("if a; then b; fi"), "[if [a] then [b]]");
+        assert_eq!(parsed("if a; then b; elif c && d; then e; else f; g; fi > out"),
+                   "[if [a] then [b] if [c; && d] then [e] else [f; g] >out]");
+        assert_eq!(parsed("if a\nthen\n  b\nfi"), "[if [a] then [b]]");
+    }
+
+    #[test]
+    fn parses_while_and_until() {
+        assert_eq!(parsed("while a; do b; done | c"), "[while [a] do [b] | c]");
+        assert_eq!(parsed("until a; do b; done &"), "[until [a] do [b] &]");
+    }
+
+    #[test]
+    fn parses_for() {
+        assert_eq!(parsed("for x in a 'b c' $y; do echo $x; done"), "[for x in a 'b c' $y do [echo $x]]");
+        assert_eq!(parsed("for x; do a; done"), "[for x do [a]]");
+        assert_eq!(parsed("for x\ndo a\ndone"), "[for x do [a]]");
+        assert_eq!(parsed("for x in; do a; done"), "[for x in  do [a]]");
+    }
+
+    #[test]
+    fn parses_case() {
+        assert_eq!(parsed("case $x in a|b) one;; (*) two;; esac"), "[case $x in a|b) [one] *) [two]]");
+        assert_eq!(parsed("case x in\n  a) one\n  ;;\n  b) two\nesac"), "[case x in a) [one] b) [two]]");
+        assert_eq!(parsed("case x in esac"), "[case x in ]");
+    }
+
+    #[test]
+    fn parses_nested_compounds() {
+        assert_eq!(parsed("for x in a; do if b; then while c; do d; done; fi; done"),
+                   "[for x in a do [if [b] then [while [c] do [d]]]]");
+    }
+
+    #[test]
+    fn reserved_words_are_only_special_first() {
+        assert_eq!(parsed("echo if then fi"), "[echo if then fi]");
+        assert_eq!(parsed("if echo done; then echo fi; fi"), "[if [echo done] then [echo fi]]");
+    }
+
+    #[test]
+    fn reports_malformed_compounds() {
+        assert!(matches!(error("if a; then fi"), ParseError::Unexpected(token) if token == "fi"));
+        assert!(matches!(error("if ; then b; fi"), ParseError::MissingCommandBefore(";")));
+        assert!(matches!(error("while a; do b; done c"), ParseError::Unexpected(token) if token == "c"));
+        assert!(matches!(error("for 1 in a; do b; done"), ParseError::Unexpected(token) if token == "1"));
+        assert!(matches!(error("case x in a) b;; c d) e;; esac"), ParseError::Unexpected(token) if token == "d"));
+        assert!(matches!(error("done"), ParseError::Unexpected(token) if token == "done"));
+    }
+
+    #[test]
+    fn tells_unclosed_compounds_apart() {
+        for line in ["if a", "if a; then b", "if a; then b; else", "while a; do b", "until a", "for x in a b",
+                     "case x in a) b;;", "{ a;"] {
+            assert!(error(line).is_incomplete(), "{:?} should continue on the next line", line);
+        }
+    }
+}
@@ -32,69 +32,98 @@
 // 4. Persistent aliases (save aliases in a file and load it when you run the shell again)
 
 use std::env;
-use std::io;
-use std::io::Write;
+use std::path::Path;
 
-use crate::command::Command;
+use crate::alias::AliasFile;
+use crate::editor::LineEditor;
+use crate::functions::FunctionFile;
+use crate::history::{DEFAULT_HISTORY_SIZE, HistoryFile};
+use crate::streams::Streams;
 use crate::terminal::Terminal;
 
+mod alias;
 mod command;
+mod completion;
+mod editor;
+mod expand;
+mod functions;
+mod glob;
+mod history;
+mod history_expansion;
+mod jobs;
+mod lexer;
+mod list;
+mod options;
+mod pipeline;
+mod status;
+mod streams;
 mod terminal;
+mod variables;
+mod versioned_file;
+
+const HISTORY_FILE_NAME: &str = ".rust_terminal_history";
+const ALIAS_FILE_NAME: &str = ".rust_terminal_aliases";
+const FUNCTION_FILE_NAME: &str = ".rust_terminal_functions";
+// Used when the shell is started without a PATH
+const DEFAULT_SYSTEM_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
 
 fn main() {
-    let cwd = get_current_working_directory();
     let hd = get_current_home_directory();
-    let mut terminal: Terminal;
-
-    if hd.is_some() && cwd.is_some() {
-        terminal = Terminal::new(hd.unwrap(), cwd.unwrap());
+    let system_path = env::var("PATH").unwrap_or_else(|_| String::from(DEFAULT_SYSTEM_PATH));
+    let (mut terminal, hd): (Terminal, String) = if let Some(hd) = hd {
+        (Terminal::new(hd.clone(), system_path), hd)
     } else {
         eprintln!("Failed to initialize a terminal instance!");
-        eprintln!("Home or working directory not set.");
+        eprintln!("Home directory not set.");
         return;
-    }
-
-    startup_util();
+    };
 
+    startup_util(&mut terminal, &hd);
 
+    let mut editor = LineEditor::new();
     loop {
-        let mut user_input = String::new();
-        print!("({}) > ", terminal.working_dir);
-        io::stdout().flush().expect("Failed to flush output");
-        io::stdin().read_line(&mut user_input).expect("Failed to read user input");
-        let cmd_option: Option<(Command, String)> = terminal.parse_user_input(user_input);
-        terminal.run_command(cmd_option);
+        terminal.report_finished_jobs();
+        let prompt = terminal.prompt();
+        let mut user_input = match editor.read_line(prompt.as_str(), &terminal).expect("Failed to read user input") {
+            Some(user_input) => user_input,
+            // End of input, treat it like exit
+            None => return,
+        };
+        while terminal.is_incomplete(user_input.as_str()) {
+            let prompt = terminal.continuation_prompt();
+            match editor.read_line(prompt.as_str(), &terminal).expect("Failed to read user input") {
+                Some(line) => {
+                    user_input.push('\n');
+                    user_input.push_str(line.as_str());
+                }
+                // Running what was read reports what is left open
+                None => break,
+            }
+        }
+        terminal.run_input(user_input.as_str(), &mut Streams::inherit());
     }
 }
 
 
 
 
-fn startup_util() {
-    // TODO load history
-    // TODO load aliases
-}
-
-fn get_current_working_directory() -> Option<String> {
-    return match env::current_dir() {
-        Ok(work_dir) => {
-            let cwd = work_dir.to_str();
-            if cwd.is_some() {
-                Some(String::from(cwd.unwrap()))
-            } else {
-                None
-            }
-        }
-        Err(_) => {
-            None
-        }
-    };
+fn startup_util(terminal: &mut Terminal, home_dir: &str) {
+    terminal.jobs.enable_job_control();
+    // The shell starts in the home directory, whichever directory it was started from
+    if let Err(error) = terminal.set_working_dir(String::from(home_dir)) {
+        eprintln!("Failed to change to the home directory: {}", error);
+    }
+    // HISTSIZE bounds both the in-memory history and the history file
+    let history_size = env::var("HISTSIZE").ok()
+        .and_then(|size| size.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_HISTORY_SIZE);
+    terminal.load_history(HistoryFile::new(Path::new(home_dir).join(HISTORY_FILE_NAME), history_size));
+    terminal.load_functions(FunctionFile::new(Path::new(home_dir).join(FUNCTION_FILE_NAME)));
+    terminal.load_aliases(AliasFile::new(Path::new(home_dir).join(ALIAS_FILE_NAME)));
 }
 
+#[allow(clippy::needless_return)]
 fn get_current_home_directory() -> Option<String> {
-    // TODO if HOME is not found check USERPROFILE for win, HOMEPATH for linux
-    return match env::var("USERPROFILE") {
-        Ok(home_dir) => Some(home_dir),
-        Err(_) => None
-    };
+    // HOME on unix, USERPROFILE on windows
+    return env::var("HOME").or_else(|_| env::var("USERPROFILE")).ok();
 }
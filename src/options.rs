@@ -0,0 +1,58 @@
+use std::collections::HashSet;
+
+/// Settings turned on with `set -o <name>` and off with `set +o <name>`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ShellOption {
+    // A pattern matching nothing is removed instead of kept as it is
+    NullGlob,
+    // A pattern matching nothing is an error
+    FailGlob,
+    // The prompt shows the status of the last command when it failed
+    ShowStatus,
+    // Functions are written to the function file whenever they change, to be defined
+    // again in the next session. The file records whether the option is on, so the
+    // next session only loads them when it is
+    SaveFunctions,
+}
+
+impl ShellOption {
+    pub const ALL: [ShellOption; 4] = [ShellOption::FailGlob, ShellOption::NullGlob, ShellOption::SaveFunctions,
+                                       ShellOption::ShowStatus];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShellOption::NullGlob => "nullglob",
+            ShellOption::FailGlob => "failglob",
+            ShellOption::ShowStatus => "showstatus",
+            ShellOption::SaveFunctions => "savefunctions",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ShellOption> {
+        ShellOption::ALL.into_iter().find(|option| option.name() == name)
+    }
+}
+
+/// The shell options that are turned on, all of them start off.
+#[derive(Clone)]
+pub(crate) struct Options {
+    enabled: HashSet<ShellOption>,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options { enabled: HashSet::new() }
+    }
+
+    pub fn is_set(&self, option: ShellOption) -> bool {
+        self.enabled.contains(&option)
+    }
+
+    pub fn set(&mut self, option: ShellOption, on: bool) {
+        if on {
+            self.enabled.insert(option);
+        } else {
+            self.enabled.remove(&option);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::command::Command;
+use crate::jobs;
+use crate::jobs::{Job, WaitOutcome};
+use crate::lexer::{RedirectKind, Word};
+use crate::list::Compound;
+use crate::status::Status;
+use crate::streams::{errln, Input, Output, Streams};
+use crate::terminal::Terminal;
+use crate::variables::Variable;
+
+/// The stages of `a | b | c`, in order.
+#[derive(Clone)]
+pub(crate) struct Pipeline {
+    pub stages: Vec<Stage<Word>>,
+    // Ended with `&`
+    pub background: bool,
+    // The pipeline as it was written, for job notices
+    pub text: String,
+}
+
+/// A single command of a pipeline with the variable assignments written before it and
+/// the redirections written after it. Stages are parsed with unexpanded words and run
+/// once their words were expanded. A subshell or group has no words of its own.
+#[derive(Clone)]
+pub(crate) struct Stage<W = String> {
+    // NAME=value, set for this command only
+    pub assignments: Vec<(String, W)>,
+    pub words: Vec<W>,
+    pub redirects: Vec<Redirect<W>>,
+    pub compound: Option<Compound>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Redirect<W = String> {
+    pub kind: RedirectKind,
+    // The file to redirect to or from, `2>&1` has none
+    pub target: Option<W>,
+}
+
+impl<W> Stage<W> {
+    pub fn new() -> Stage<W> {
+        Stage { assignments: Vec::new(), words: Vec::new(), redirects: Vec::new(), compound: None }
+    }
+}
+
+pub(crate) enum ParseError {
+    // A `|` with no command on one of its sides
+    MissingCommand,
+    // A redirection operator that is not followed by a file name
+    MissingRedirectTarget,
+    // A list operator with no command before it
+    MissingCommandBefore(&'static str),
+    // A `&&` or `||` with no command after it
+    MissingCommandAfter(&'static str),
+    // A compound command that is never closed, named by the word or `(` opening it
+    Unclosed(&'static str),
+    // A token where it can not be used, such as a word after a subshell
+    Unexpected(String),
+}
+
+impl ParseError {
+    /// Whether the tokens ran out before the line was complete, so more lines may still
+    /// complete it.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, ParseError::MissingCommandAfter(_) | ParseError::Unclosed(_))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingCommand => write!(f, "Syntax error: expected a command on both sides of \"|\""),
+            ParseError::MissingRedirectTarget => write!(f, "Syntax error: expected a file name after a redirection"),
+            ParseError::MissingCommandBefore(operator) => write!(f, "Syntax error: expected a command before \"{}\"", operator),
+            ParseError::MissingCommandAfter(operator) => write!(f, "Syntax error: expected a command after \"{}\"", operator),
+            ParseError::Unclosed(opening) => write!(f, "Syntax error: \"{}\" is never closed", opening),
+            ParseError::Unexpected(token) if token == "\n" => write!(f, "Syntax error: unexpected line break"),
+            ParseError::Unexpected(token) => write!(f, "Syntax error: unexpected \"{}\"", token),
+        }
+    }
+}
+
+/// Points the streams of a stage at the files named by its redirections, in the order
+/// they were written, so `> file 2>&1` sends both streams to the file while `2>&1 > file`
+/// only sends stdout there. Paths are relative to the working directory.
+fn apply_redirects(redirects: &[Redirect], terminal: &Terminal, streams: &mut Streams) -> Result<(), String> {
+    for redirect in redirects {
+        if redirect.kind == RedirectKind::ErrorToOutput {
+            streams.stderr = streams.stdout.try_clone().map_err(|error| error.to_string())?;
+            continue;
+        }
+        let target = redirect.target.as_deref().unwrap_or_default();
+        let path = Path::new(&terminal.working_dir).join(target);
+        let append = matches!(redirect.kind, RedirectKind::Append | RedirectKind::ErrorAppend);
+        let file = if redirect.kind == RedirectKind::Input {
+            File::open(&path)
+        } else {
+            OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(&path)
+        }.map_err(|error| format!("{}: {}", target, error))?;
+
+        match redirect.kind {
+            RedirectKind::Input => streams.stdin = Input::File(file),
+            RedirectKind::Output | RedirectKind::Append => streams.stdout = Output::File(file),
+            RedirectKind::Error | RedirectKind::ErrorAppend => streams.stderr = Output::File(file),
+            RedirectKind::OutputAndError => {
+                streams.stderr = Output::File(file.try_clone().map_err(|error| error.to_string())?);
+                streams.stdout = Output::File(file);
+            }
+            RedirectKind::ErrorToOutput => {}
+        }
+    }
+    Ok(())
+}
+
+/// Runs the resolved stages of a pipeline, each with the words it was invoked with and
+/// its redirections applied on top of the pipes. A stage without a command is a subshell
+/// or group.
+///
+/// Every external program is spawned straight away, sharing one process group when job
+/// control is on, with its stdout piped into the next stage so they all run concurrently.
+/// A subshell or group that is not the last stage runs the same way in a forked copy of
+/// the shell. Builtins run in the shell itself; when they are not the last stage their
+/// output is collected and fed to the next stage's stdin. The spawned programs are then
+/// waited for as a single job, or added to the job table when running in the background.
+/// The status of the pipeline is the status of its last stage, which is also kept as `$?`.
+pub(crate) fn run(stages: Vec<(Option<Command>, Stage)>, background: bool, command_line: &str,
+                  terminal: &mut Terminal, streams: &mut Streams) -> Status {
+    let status = run_stages(stages, background, command_line, terminal, streams);
+    terminal.last_status = status;
+    status
+}
+
+fn run_stages(stages: Vec<(Option<Command>, Stage)>, background: bool, command_line: &str,
+              terminal: &mut Terminal, streams: &mut Streams) -> Status {
+    let count = stages.len();
+    let mut children: Vec<RunningChild> = Vec::new();
+    // Every process started, spawned programs and forked compound commands alike
+    let mut pids: Vec<i32> = Vec::new();
+    let mut pgid: Option<i32> = None;
+    let mut last_pid: Option<i32> = None;
+    let mut last_program: Option<&str> = None;
+    let mut stdin = streams.stdin.take();
+    // The status of the last stage when it is a builtin or could not be started
+    let mut last_stage_status = Status::FAILURE;
+
+    for (index, (command, stage)) in stages.iter().enumerate() {
+        let last = index + 1 == count;
+        let piped = matches!(command, Some(Command::External(_)) | None);
+        let (stdout, next_stdin) = match (last, piped) {
+            (true, _) => (streams.stdout.try_clone(), Input::Inherit),
+            (false, true) => match io::pipe() {
+                Ok((reader, writer)) => (Ok(Output::Pipe(writer)), Input::Pipe(reader)),
+                Err(error) => (Err(error), Input::Inherit),
+            },
+            (false, false) => (Ok(Output::buffer()), Input::Inherit),
+        };
+        let (stdout, stderr) = match (stdout, streams.stderr.try_clone()) {
+            (Ok(stdout), Ok(stderr)) => (stdout, stderr),
+            (Err(error), _) | (_, Err(error)) => {
+                errln!(streams, "Failed to set up the pipeline: {}", error);
+                break;
+            }
+        };
+        let mut stage_streams = Streams { stdin: stdin.take(), stdout, stderr };
+        // Dropping the stage streams closes the shell's copy of a pipe's write end
+        stdin = next_stdin;
+        if let Err(error) = apply_redirects(stage.redirects.as_slice(), terminal, &mut stage_streams) {
+            errln!(streams, "{}", error);
+            continue;
+        }
+
+        let process_group = if terminal.jobs.is_job_control() { Some(pgid.unwrap_or(0)) } else { None };
+        let started = if let Some(Command::External(program)) = &command {
+            match terminal.find_executable(program) {
+                Some(path) => match spawn_external(path.as_path(), stage, terminal, &mut stage_streams, process_group) {
+                    Ok(child) => {
+                        let pid = child.id();
+                        if last {
+                            last_pid = Some(pid);
+                            last_program = Some(program.as_str());
+                        }
+                        children.push(child);
+                        Some(pid)
+                    }
+                    Err(error) => {
+                        errln!(streams, "Failed to run \"{}\": {}", program, error);
+                        None
+                    }
+                },
+                None => {
+                    errln!(streams, "Could not find \"{}\" on the system path.", program);
+                    None
+                }
+            }
+        } else if command.is_none() && !last {
+            match fork_compound(stage, terminal, &mut stage_streams, &mut stdin, process_group) {
+                Ok(pid) => Some(pid),
+                Err(error) => {
+                    errln!(streams, "Failed to start a subshell: {}", error);
+                    None
+                }
+            }
+        } else {
+            // Assignments before a builtin only last as long as it runs
+            let saved: Vec<(&String, Option<Variable>)> = stage.assignments.iter()
+                .map(|(name, value)| {
+                    let saved = terminal.variables.unset(name);
+                    terminal.variables.export(name, Some(value.clone()));
+                    (name, saved)
+                })
+                .collect();
+            let stage_status = match command {
+                Some(command) => command.get_command().execute(stage.words.clone(), terminal, &mut stage_streams),
+                None => terminal.run_compound(stage.compound.as_ref().expect("a stage without a command is compound"),
+                                              &mut stage_streams),
+            };
+            for (name, variable) in saved.into_iter().rev() {
+                terminal.variables.restore(name, variable);
+            }
+            if last {
+                last_stage_status = stage_status;
+            } else {
+                stdin = Input::Bytes(stage_streams.stdout.take_bytes());
+            }
+            None
+        };
+        if let Some(pid) = started {
+            if process_group.is_some() && pgid.is_none() {
+                pgid = Some(pid);
+                if !background {
+                    terminal.jobs.give_terminal(pgid);
+                }
+            }
+            pids.push(pid);
+        }
+    }
+
+    if pids.is_empty() {
+        return last_stage_status;
+    }
+    let job = Job::new(pgid, pids.clone(), last_pid, command_line);
+    if background {
+        let id = terminal.jobs.add(job);
+        eprintln!("[{}] {}", id, pids.last().unwrap());
+        return Status::SUCCESS;
+    }
+    match terminal.jobs.wait_foreground(job) {
+        WaitOutcome::Exited(status) => {
+            for child in children {
+                if let Err(error) = child.finish(streams) {
+                    errln!(streams, "Failed to collect output: {}", error);
+                }
+            }
+            if let (Some(status), Some(program)) = (status, last_program) {
+                if !status.success() && !terminal.in_condition() {
+                    report_exit_status(program, &status);
+                }
+            }
+            status.map(|status| Status::from_exit_status(&status)).unwrap_or(last_stage_status)
+        }
+        WaitOutcome::Stopped(id) => {
+            eprintln!();
+            eprintln!("[{}]+  Stopped                 {}", id, command_line);
+            Status::signaled(libc::SIGTSTP)
+        }
+    }
+}
+
+/// Runs the compound command of a stage in a forked copy of the shell with the given
+/// streams, without waiting for it, and gives the pid of the copy. The copy exits with
+/// the status of the compound command. `pipe_reader` is the read end of the pipe the
+/// stage writes to, which the copy closes so the stage sees when its reader is gone.
+/// A process group of 0 starts a new group led by the copy.
+fn fork_compound(stage: &Stage, terminal: &mut Terminal, streams: &mut Streams, pipe_reader: &mut Input,
+                 process_group: Option<i32>) -> io::Result<i32> {
+    let compound = stage.compound.as_ref().expect("a stage without a command is compound");
+    // SAFETY: the child only runs the shell's own code, then exits without returning
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            if let Some(process_group) = process_group {
+                unsafe {
+                    libc::setpgid(0, process_group);
+                }
+            }
+            jobs::reset_signals();
+            // Writing to a pipe whose reader exited ends the copy, as it would a program
+            unsafe {
+                libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+            }
+            drop(pipe_reader.take());
+            terminal.become_pipeline_child();
+            let status = terminal.run_compound(compound, streams);
+            let _ = streams.stdout.flush();
+            let _ = streams.stderr.flush();
+            process::exit(status.code());
+        }
+        pid => {
+            // Set from both sides so the group exists whichever process runs first
+            if let Some(process_group) = process_group {
+                unsafe {
+                    libc::setpgid(pid, if process_group == 0 { pid } else { process_group });
+                }
+            }
+            Ok(pid)
+        }
+    }
+}
+
+/// A spawned program along with the threads copying its output into buffers.
+pub(crate) struct RunningChild {
+    child: process::Child,
+    stdout_copy: Option<JoinHandle<Vec<u8>>>,
+    stderr_copy: Option<JoinHandle<Vec<u8>>>,
+}
+
+impl RunningChild {
+    pub fn id(&self) -> i32 {
+        self.child.id() as i32
+    }
+
+    /// Once the program exited, appends anything it wrote to a buffer output to the
+    /// matching stream.
+    pub fn finish(mut self, streams: &mut Streams) -> io::Result<()> {
+        if let Some(copy) = self.stdout_copy.take() {
+            streams.stdout.write_all(copy.join().unwrap_or_default().as_slice())?;
+        }
+        if let Some(copy) = self.stderr_copy.take() {
+            streams.stderr.write_all(copy.join().unwrap_or_default().as_slice())?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts the program of a stage in the working directory with the given streams,
+/// without waiting for it. Its environment is made of the exported variables and the
+/// stage's assignments. Stdin is taken from the streams; stdout and stderr are shared.
+/// A process group of 0 starts a new group led by the program.
+fn spawn_external(path: &Path, stage: &Stage, terminal: &Terminal, streams: &mut Streams,
+                  process_group: Option<i32>) -> io::Result<RunningChild> {
+    let (stdin, stdin_bytes) = streams.stdin.take().into_stdio();
+    let mut command = process::Command::new(path);
+    command.args(&stage.words[1..])
+        .env_clear()
+        .envs(terminal.variables.exported())
+        .envs(stage.assignments.iter().map(|(name, value)| (name, value)))
+        .current_dir(&terminal.working_dir)
+        .stdin(stdin)
+        .stdout(streams.stdout.to_stdio()?)
+        .stderr(streams.stderr.to_stdio()?);
+    if let Some(process_group) = process_group {
+        command.process_group(process_group);
+    }
+    unsafe {
+        command.pre_exec(|| {
+            jobs::reset_signals();
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+
+    if let (Some(bytes), Some(mut child_stdin)) = (stdin_bytes, child.stdin.take()) {
+        // Written from a thread so a child that fills its stdout before reading can not
+        // block the shell. Dropping the handle afterwards signals end of input.
+        thread::spawn(move || {
+            let _ = child_stdin.write_all(bytes.as_slice());
+        });
+    }
+    let stdout_copy = child.stdout.take().map(copy_to_end);
+    let stderr_copy = child.stderr.take().map(copy_to_end);
+    Ok(RunningChild { child, stdout_copy, stderr_copy })
+}
+
+fn copy_to_end<R: Read + Send + 'static>(mut reader: R) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes: Vec<u8> = Vec::new();
+        let _ = reader.read_to_end(&mut bytes);
+        bytes
+    })
+}
+
+// Reported by the shell itself, so it never ends up in a redirected stderr. A program
+// ended by writing to a pipe nothing reads anymore is not worth a notice.
+fn report_exit_status(program: &str, status: &process::ExitStatus) {
+    if let Some(code) = status.code() {
+        eprintln!("\"{}\" exited with status {}", program, code);
+        return;
+    }
+    if let Some(signal) = status.signal().filter(|signal| *signal != libc::SIGPIPE) {
+        eprintln!("\"{}\" terminated by signal {}", program, signal);
+    }
+}
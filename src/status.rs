@@ -0,0 +1,49 @@
+use std::os::unix::process::ExitStatusExt;
+use std::process;
+
+/// How a command finished, the code it exited with or the signal that ended or stopped
+/// it. This is what `$?` expands to and what `&&` and `||` test.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Status {
+    code: i32,
+    // The signal that ended or stopped the command, its code being 128 plus the signal
+    signal: Option<i32>,
+}
+
+impl Status {
+    pub const SUCCESS: Status = Status { code: 0, signal: None };
+    pub const FAILURE: Status = Status { code: 1, signal: None };
+    // The line could not be parsed
+    pub const SYNTAX_ERROR: Status = Status { code: 2, signal: None };
+    // The command could not be found
+    pub const NOT_FOUND: Status = Status { code: 127, signal: None };
+
+    pub fn exited(code: i32) -> Status {
+        Status { code, signal: None }
+    }
+
+    pub fn signaled(signal: i32) -> Status {
+        Status { code: 128 + signal, signal: Some(signal) }
+    }
+
+    /// The status of a program that finished, normally or killed by a signal.
+    pub fn from_exit_status(status: &process::ExitStatus) -> Status {
+        match (status.code(), status.signal()) {
+            (Some(code), _) => Status::exited(code),
+            (None, Some(signal)) => Status::signaled(signal),
+            (None, None) => Status::SUCCESS,
+        }
+    }
+
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    pub fn signal(&self) -> Option<i32> {
+        self.signal
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.code == 0
+    }
+}
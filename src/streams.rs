@@ -0,0 +1,134 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::io::{PipeReader, PipeWriter, Write};
+use std::process::Stdio;
+use std::rc::Rc;
+
+/// Where a command reads its input from.
+pub(crate) enum Input {
+    Inherit,
+    File(File),
+    Pipe(PipeReader),
+    // Output collected from a builtin earlier in a pipeline
+    Bytes(Vec<u8>),
+}
+
+/// Where a command writes its output to. Buffers are shared, so every clone of a
+/// buffer output appends to the same bytes.
+pub(crate) enum Output {
+    Stdout,
+    Stderr,
+    File(File),
+    Pipe(PipeWriter),
+    Buffer(Rc<RefCell<Vec<u8>>>),
+}
+
+/// The standard streams a builtin writes to, or an external program is spawned with.
+pub(crate) struct Streams {
+    pub stdin: Input,
+    pub stdout: Output,
+    pub stderr: Output,
+}
+
+impl Streams {
+    pub fn inherit() -> Streams {
+        Streams { stdin: Input::Inherit, stdout: Output::Stdout, stderr: Output::Stderr }
+    }
+}
+
+impl Input {
+    pub fn take(&mut self) -> Input {
+        std::mem::replace(self, Input::Inherit)
+    }
+
+    /// The stdio for a child process, plus any bytes that still need to be written to
+    /// the child's stdin once it is running.
+    pub fn into_stdio(self) -> (Stdio, Option<Vec<u8>>) {
+        match self {
+            Input::Inherit => (Stdio::inherit(), None),
+            Input::File(file) => (Stdio::from(file), None),
+            Input::Pipe(reader) => (Stdio::from(reader), None),
+            Input::Bytes(bytes) => (Stdio::piped(), Some(bytes)),
+        }
+    }
+}
+
+impl Output {
+    pub fn buffer() -> Output {
+        Output::Buffer(Rc::new(RefCell::new(Vec::new())))
+    }
+
+    pub fn try_clone(&self) -> io::Result<Output> {
+        Ok(match self {
+            Output::Stdout => Output::Stdout,
+            Output::Stderr => Output::Stderr,
+            Output::File(file) => Output::File(file.try_clone()?),
+            Output::Pipe(writer) => Output::Pipe(writer.try_clone()?),
+            Output::Buffer(buffer) => Output::Buffer(Rc::clone(buffer)),
+        })
+    }
+
+    /// Empties a buffer output, returning what was written to it.
+    pub fn take_bytes(&self) -> Vec<u8> {
+        match self {
+            Output::Buffer(buffer) => buffer.take(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The stdio for a child process. Buffers can not be handed to a child directly, so
+    /// they are piped and the child's output must be copied in with `Output::write_all`.
+    pub fn to_stdio(&self) -> io::Result<Stdio> {
+        Ok(match self {
+            Output::Stdout => Stdio::from(io::stdout()),
+            Output::Stderr => Stdio::from(io::stderr()),
+            Output::File(file) => Stdio::from(file.try_clone()?),
+            Output::Pipe(writer) => Stdio::from(writer.try_clone()?),
+            Output::Buffer(_) => Stdio::piped(),
+        })
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout => io::stdout().write(buf),
+            Output::Stderr => io::stderr().write(buf),
+            Output::File(file) => file.write(buf),
+            Output::Pipe(writer) => writer.write(buf),
+            Output::Buffer(buffer) => buffer.borrow_mut().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout => io::stdout().flush(),
+            Output::Stderr => io::stderr().flush(),
+            Output::File(file) => file.flush(),
+            Output::Pipe(writer) => writer.flush(),
+            Output::Buffer(_) => Ok(()),
+        }
+    }
+}
+
+/// `println!` for a builtin's stdout. A failed write, e.g. to a pipe whose reader has
+/// already exited, is ignored instead of panicking like `println!` would.
+macro_rules! outln {
+    ($streams:expr) => {{
+        let _ = std::io::Write::write_all(&mut $streams.stdout, b"\n");
+    }};
+    ($streams:expr, $($arg:tt)*) => {{
+        let _ = std::io::Write::write_fmt(&mut $streams.stdout, format_args!("{}\n", format_args!($($arg)*)));
+    }};
+}
+
+/// `eprintln!` for a builtin's stderr.
+macro_rules! errln {
+    ($streams:expr, $($arg:tt)*) => {{
+        let _ = std::io::Write::write_fmt(&mut $streams.stderr, format_args!("{}\n", format_args!($($arg)*)));
+    }};
+}
+
+pub(crate) use errln;
+pub(crate) use outln;
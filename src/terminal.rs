@@ -1,59 +1,517 @@
-use std;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+use crate::alias;
+use crate::alias::{AliasFile, Aliases};
 use crate::command::Command;
+use crate::expand;
+use crate::functions::{Function, FunctionFile, Functions};
+use crate::glob;
+use crate::history::{HistoryEntry, HistoryFile, HistoryFilter, RunDetails};
+use crate::history_expansion;
+use crate::jobs;
+use crate::jobs::JobTable;
+use crate::lexer;
+use crate::lexer::{Token, Word};
+use crate::list;
+use crate::list::{CommandList, Compound, Connector};
+use crate::options::{Options, ShellOption};
+use crate::pipeline;
+use crate::pipeline::{Pipeline, Stage};
+use crate::status::Status;
+use crate::streams::{errln, outln, Input, Output, Streams};
+use crate::variables::{Variable, Variables};
+
+// How deep functions may call each other, so runaway recursion fails instead of
+// overflowing the stack
+const MAX_FUNCTION_DEPTH: usize = 100;
+
+// How deep history entries may re-run each other, so an alias re-running the entry that
+// uses it fails instead of overflowing the stack
+const MAX_RERUN_DEPTH: usize = 100;
+
+/// What `break` or `continue` asks of the loops running, with how many loops are still
+/// to be left.
+#[derive(Clone, Copy)]
+pub(crate) enum LoopControl {
+    Break(usize),
+    // The last loop left goes on with its next iteration
+    Continue(usize),
+}
+
+// What a loop does once a part of it ran
+enum LoopStep {
+    Go,
+    NextIteration,
+    Stop,
+}
 
 pub(crate) struct Terminal {
     pub working_dir: String,
-    pub system_path: String,
-    history: Vec<(Command, String)>,
-    aliases: HashMap<String, (Command, Option<String>)>,
-
+    pub jobs: JobTable,
+    pub variables: Variables,
+    pub options: Options,
+    // Directories saved by pushd, the last one being the top of the stack
+    pub dir_stack: Vec<String>,
+    // Exit status of the last command, `$?`
+    pub last_status: Status,
+    // Arguments of the function running, `$1`, `$2` and so on
+    pub positional: Vec<String>,
+    history: Vec<HistoryEntry>,
+    aliases: Aliases,
+    functions: Functions,
+    history_file: Option<HistoryFile>,
+    alias_file: Option<AliasFile>,
+    function_file: Option<FunctionFile>,
+    // For each function running, the variables made local in it and what they replaced
+    frames: Vec<Vec<(String, Option<Variable>)>>,
+    // The status a function ends with once `return` ran in it
+    return_status: Option<Status>,
+    // Set for the copy of the shell a `( ... )` subshell runs in
+    subshell: bool,
+    // The status a subshell ends with once `exit` ran in it
+    exit_status: Option<Status>,
+    // The status of the last command substitution of the pipeline being expanded
+    substitution_status: Option<Status>,
+    // Set while a status only tested runs, where failing is expected: the condition of an
+    // if, while or until, a pipeline followed by `&&` or `||` or a command substitution
+    in_condition: bool,
+    // How many loops are running in the function running, or outside of any function
+    loop_depth: usize,
+    // Set once `break` or `continue` ran, until the loops it leaves stopped
+    loop_control: Option<LoopControl>,
+    // Set once a loop found Ctrl-C interrupted the shell itself, so nothing else of the
+    // line runs
+    interrupted: bool,
+    // How many history entries are being re-run, each by the one before
+    rerun_depth: usize,
 }
 
 impl Terminal {
     pub fn new(home_dir: String, system_dir: String) -> Terminal {
-        Terminal {
+        let mut terminal = Terminal {
             working_dir: home_dir,
-            system_path: system_dir,
+            jobs: JobTable::new(),
+            variables: Variables::from_env(),
+            options: Options::new(),
+            dir_stack: Vec::new(),
+            last_status: Status::SUCCESS,
+            positional: Vec::new(),
             history: Vec::new(),
             aliases: HashMap::new(),
+            functions: HashMap::new(),
+            history_file: None,
+            alias_file: None,
+            function_file: None,
+            frames: Vec::new(),
+            return_status: None,
+            subshell: false,
+            exit_status: None,
+            substitution_status: None,
+            in_condition: false,
+            loop_depth: 0,
+            loop_control: None,
+            interrupted: false,
+            rerun_depth: 0,
+        };
+        terminal.set_system_path(system_dir);
+        terminal
+    }
+
+    // A copy of the shell for a subshell to run in. It starts with the variables, options,
+    // aliases, functions and history of the shell but writes to none of their files, and
+    // has no jobs.
+    fn subshell(&self) -> Terminal {
+        Terminal {
+            working_dir: self.working_dir.clone(),
+            jobs: self.jobs.for_subshell(),
+            variables: self.variables.clone(),
+            options: self.options.clone(),
+            dir_stack: self.dir_stack.clone(),
+            last_status: self.last_status,
+            positional: self.positional.clone(),
+            history: self.history.clone(),
+            aliases: self.aliases.clone(),
+            functions: self.functions.clone(),
+            history_file: None,
+            alias_file: None,
+            function_file: None,
+            // Still in the functions that were running, so `return` ends the subshell
+            frames: self.frames.iter().map(|_| Vec::new()).collect(),
+            return_status: None,
+            subshell: true,
+            exit_status: None,
+            substitution_status: None,
+            in_condition: self.in_condition,
+            // Still in the loops that were running, though `break` only ends the subshell
+            loop_depth: self.loop_depth,
+            loop_control: None,
+            interrupted: false,
+            rerun_depth: self.rerun_depth,
+        }
+    }
+
+    /// Turns a forked copy of the shell into a subshell running a stage of a pipeline.
+    /// Like any subshell it writes to none of the shell's files, and it leaves the
+    /// terminal and job control to the shell.
+    pub fn become_pipeline_child(&mut self) {
+        self.jobs = JobTable::new();
+        self.history_file = None;
+        self.alias_file = None;
+        self.function_file = None;
+        self.subshell = true;
+    }
+
+    pub fn is_subshell(&self) -> bool {
+        self.subshell
+    }
+
+    /// Whether a condition or other tested command is running, whose programs failing is
+    /// not worth a notice.
+    pub fn in_condition(&self) -> bool {
+        self.in_condition
+    }
+
+    /// Ends a subshell once the command running in it finished, with the given status.
+    pub fn exit_subshell(&mut self, status: Status) {
+        self.exit_status = Some(status);
+    }
+
+    /// Makes a directory the working directory of the shell and of the programs it
+    /// runs, keeping the previous one in OLDPWD.
+    pub fn set_working_dir(&mut self, dir: String) -> io::Result<()> {
+        env::set_current_dir(&dir)?;
+        let previous = std::mem::replace(&mut self.working_dir, dir);
+        self.variables.export("OLDPWD", Some(previous));
+        self.variables.export("PWD", Some(self.working_dir.clone()));
+        Ok(())
+    }
+
+    /// The prompt shown before each line, with the status of the last command in front
+    /// of it when it failed and the `showstatus` option is set.
+    pub fn prompt(&self) -> String {
+        let prompt = format!("({}) > ", self.working_dir);
+        if !self.options.is_set(ShellOption::ShowStatus) || self.last_status.is_success() {
+            return prompt;
+        }
+        match self.last_status.signal() {
+            Some(signal) => format!("[{} signal {}] {}", self.last_status.code(), signal, prompt),
+            None => format!("[{}] {}", self.last_status.code(), prompt),
+        }
+    }
+
+    /// The prompt shown before each further line of input that is not complete yet, taken
+    /// from PS2.
+    pub fn continuation_prompt(&self) -> String {
+        String::from(self.variables.get("PS2").unwrap_or("> "))
+    }
+
+    /// Whether input ends inside a quote or compound command, or after an operator that
+    /// needs a command after it, so it goes on with the next line.
+    pub fn is_incomplete(&self, input: &str) -> bool {
+        let tokens = match lexer::tokenize(input) {
+            Ok(tokens) => tokens,
+            // Every lexing error is something left open at the end of the input
+            Err(_) => return true,
+        };
+        match alias::expand_aliases(tokens, &self.aliases) {
+            Ok(tokens) => matches!(list::parse(tokens), Err(error) if error.is_incomplete()),
+            Err(_) => false,
+        }
+    }
+
+    /// The colon separated directories programs are looked up in, kept in the PATH
+    /// variable so the programs that are run see the same path.
+    pub fn system_path(&self) -> &str {
+        self.variables.get("PATH").unwrap_or_default()
+    }
+
+    pub fn set_system_path(&mut self, path: String) {
+        self.variables.export("PATH", Some(path));
+    }
+
+    /// Adds an entry to the history of this session, writing it to the history file
+    /// shared with other shells straight away. Lines left out by HISTCONTROL and
+    /// HISTIGNORE are neither kept nor written.
+    pub fn append(&mut self, mut entry: HistoryEntry) {
+        let filter = HistoryFilter::new(self.variables.get("HISTCONTROL").unwrap_or_default(),
+                                        self.variables.get("HISTIGNORE").unwrap_or_default());
+        let previous = self.history.last().map(|previous| previous.line.as_str());
+        if filter.ignores(entry.line.as_str(), previous) {
+            return;
+        }
+        entry.line = String::from(entry.line.trim());
+        if filter.erase_dups {
+            self.history.retain(|known| known.line != entry.line);
+        }
+        if let Some(history_file) = &self.history_file {
+            let result = if filter.erase_dups { history_file.append_erasing(&entry) } else { history_file.append(&entry) };
+            match result {
+                Ok(()) => entry.saved = true,
+                Err(error) => eprintln!("Failed to save history: {}", error),
+            }
+            let overflow = (self.history.len() + 1).saturating_sub(history_file.max_size());
+            self.history.drain(..overflow);
+        }
+        self.history.push(entry);
+    }
+
+    /// Removes the entry at an index of the history, from the history file as well.
+    pub fn remove_history_entry(&mut self, index: usize) -> Option<HistoryEntry> {
+        if index >= self.history.len() {
+            return None;
+        }
+        let entry = self.history.remove(index);
+        if let Some(history_file) = &self.history_file {
+            if let Err(error) = history_file.remove(&entry) {
+                eprintln!("Failed to save history: {}", error);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Empties the history and the history file.
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+        if let Some(history_file) = &self.history_file {
+            if let Err(error) = history_file.clear() {
+                eprintln!("Failed to save history: {}", error);
+            }
+        }
+    }
+
+    /// Writes the entries of this session that could not be written when they ran to
+    /// the history file, giving how many were written.
+    pub fn save_history(&mut self) -> io::Result<usize> {
+        let history_file = match &self.history_file {
+            Some(history_file) => history_file,
+            None => return Ok(0),
+        };
+        let unsaved: Vec<&HistoryEntry> = self.history.iter().filter(|entry| !entry.saved).collect();
+        let count = unsaved.len();
+        if count > 0 {
+            history_file.append_all(unsaved.as_slice())?;
+        }
+        for entry in self.history.iter_mut() {
+            entry.saved = true;
+        }
+        Ok(count)
+    }
+
+    /// Replaces the history of this session with the contents of the history file.
+    pub fn reload_history(&mut self) -> io::Result<()> {
+        if let Some(history_file) = &self.history_file {
+            self.history = history_file.load()?;
+        }
+        Ok(())
+    }
+
+    /// Adds the entries other shells wrote to the history file since it was read to the
+    /// history of this session, ordered by when they ran, giving how many were added.
+    pub fn merge_history(&mut self) -> io::Result<usize> {
+        let history_file = match &self.history_file {
+            Some(history_file) => history_file,
+            None => return Ok(0),
+        };
+        let known: HashSet<String> = self.history.iter().map(HistoryEntry::identity).collect();
+        let new_entries: Vec<HistoryEntry> = history_file.load()?.into_iter()
+            .filter(|entry| !known.contains(&entry.identity()))
+            .collect();
+        let count = new_entries.len();
+        self.history.extend(new_entries);
+
+        // Entries that did not run, such as printed ones, stay after the entry before them
+        let mut started = i64::MIN;
+        let mut keyed: Vec<(i64, HistoryEntry)> = self.history.drain(..)
+            .map(|entry| {
+                if let Some(details) = &entry.details {
+                    started = details.started;
+                }
+                (started, entry)
+            })
+            .collect();
+        keyed.sort_by_key(|(started, _)| *started);
+        self.history = keyed.into_iter().map(|(_, entry)| entry).collect();
+        let overflow = self.history.len().saturating_sub(history_file.max_size());
+        self.history.drain(..overflow);
+        Ok(count)
+    }
+
+    /// Replaces the in-memory history with the contents of the history file, which is
+    /// then kept up to date on every append. A file that can not be read is left alone.
+    pub fn load_history(&mut self, history_file: HistoryFile) {
+        match history_file.load() {
+            Ok(history) => {
+                self.history = history;
+                self.history_file = Some(history_file);
+            }
+            Err(error) => eprintln!("Failed to load history, it will not be saved: {}", error),
+        }
+    }
+
+    /// Replaces the aliases with the contents of the alias file, which is then rewritten
+    /// whenever an alias is added or removed. Aliases whose command no longer exists are
+    /// skipped with a warning, so functions are loaded first for aliases to run them. A
+    /// file that can not be read is left alone.
+    pub fn load_aliases(&mut self, alias_file: AliasFile) {
+        match alias_file.load() {
+            Ok(aliases) => {
+                self.aliases = aliases;
+                for (name, command) in self.aliases_without_command() {
+                    eprintln!("Ignoring alias \"{}\": command \"{}\" no longer exists", name, command);
+                    self.aliases.remove(&name);
+                }
+                self.alias_file = Some(alias_file);
+            }
+            Err(error) => eprintln!("Failed to load aliases, they will not be saved: {}", error),
         }
     }
 
-    pub fn append(&mut self, command: Command, cmd_string_line: &str) {
-        self.history.push((command, String::from(cmd_string_line)));
+    // The aliases whose command is neither a builtin, function, alias nor program, with
+    // that command. Reserved words, assignments and words only known once expanded are
+    // taken as found.
+    fn aliases_without_command(&self) -> Vec<(String, String)> {
+        let mut missing: Vec<(String, String)> = self.aliases.iter()
+            .filter_map(|(name, text)| {
+                let command = match lexer::tokenize(text).ok()?.into_iter().next()? {
+                    Token::Word(word) => String::from(word.as_plain()?),
+                    _ => return None,
+                };
+                let found = command.contains('=') || matches!(command.as_str(), "{" | "if" | "while" | "until" | "for" | "case")
+                    || self.aliases.contains_key(&command) || self.resolve_command(&command).is_some();
+                if found { None } else { Some((name.clone(), command)) }
+            })
+            .collect();
+        missing.sort();
+        missing
+    }
+
+    fn save_aliases(&self) {
+        if let Some(alias_file) = &self.alias_file {
+            if let Err(error) = alias_file.save(&self.aliases) {
+                eprintln!("Failed to save aliases: {}", error);
+            }
+        }
+    }
+
+    /// Defines the functions saved in the function file when the file says they are
+    /// saved, turning the savefunctions option on. A file that can not be read is left
+    /// alone.
+    pub fn load_functions(&mut self, function_file: FunctionFile) {
+        match function_file.load() {
+            Ok(Some(functions)) => {
+                self.functions = functions;
+                self.options.set(ShellOption::SaveFunctions, true);
+                self.function_file = Some(function_file);
+            }
+            Ok(None) => self.function_file = Some(function_file),
+            Err(error) => eprintln!("Failed to load functions, they will not be saved: {}", error),
+        }
+    }
+
+    /// Turns the savefunctions option on or off, recording the choice in the function
+    /// file so the next session only loads the functions when it is on. Functions
+    /// defined before it was turned on are saved straight away.
+    pub fn set_save_functions(&mut self, on: bool) {
+        self.options.set(ShellOption::SaveFunctions, on);
+        if let Some(function_file) = &self.function_file {
+            let result = if on { function_file.save(&self.functions) } else { function_file.stop_saving() };
+            if let Err(error) = result {
+                eprintln!("Failed to save functions: {}", error);
+            }
+        }
+    }
+
+    /// Writes the functions to the function file when the savefunctions option is set.
+    fn save_functions(&self) {
+        if !self.options.is_set(ShellOption::SaveFunctions) {
+            return;
+        }
+        if let Some(function_file) = &self.function_file {
+            if let Err(error) = function_file.save(&self.functions) {
+                eprintln!("Failed to save functions: {}", error);
+            }
+        }
+    }
+
+    fn define_function(&mut self, name: &str, function: Function) {
+        self.functions.insert(String::from(name), function);
+        self.save_functions();
+    }
+
+    pub fn remove_function(&mut self, name: &str) -> bool {
+        let removed = self.functions.remove(name).is_some();
+        if removed {
+            self.save_functions();
+        }
+        removed
+    }
+
+    /// The definition of a function.
+    pub fn get_function(&self, name: &str) -> Option<&str> {
+        self.functions.get(name).map(|function| function.text.as_str())
+    }
+
+    /// The names of every function, sorted.
+    pub fn get_function_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.functions.keys().cloned().collect();
+        names.sort();
+        names
     }
 
     pub fn get_history_commands(&self) -> Vec<String> {
         // TODO history should maybe include itself
-        self.history.iter().map(|item| String::from(item.1.as_str())).collect()
+        self.history.iter().map(|entry| entry.line.clone()).collect()
+    }
+
+    pub fn get_history_entries(&self) -> &[HistoryEntry] {
+        self.history.as_slice()
     }
 
 
     pub fn get_aliases_string(&self) -> Vec<String> {
-        self.aliases.iter()
-            .map(|(key, (cmd, args))| get_format(String::from(key), cmd.to_string(), args))
-                                                  .collect()
+        let mut aliases: Vec<String> = self.aliases.iter()
+            .map(|(key, text)| get_format(key, text))
+            .collect();
+        aliases.sort();
+        aliases
+    }
+
+    pub fn get_alias(&self, name: &str) -> Option<String> {
+        self.aliases.get(name).map(|text| get_format(name, text))
     }
 
-    pub fn add_alias(&mut self, name: String, command: Command, args: Option<String>) {
-        self.aliases.insert(name, (command, args));
+    pub fn add_alias(&mut self, name: String, text: String) {
+        self.aliases.insert(name, text);
+        self.save_aliases();
+    }
+
+    pub fn get_alias_names(&self) -> Vec<String> {
+        self.aliases.keys().cloned().collect()
     }
 
     pub fn is_alias_present(&self, alias: &String) -> bool {
         self.aliases.contains_key(alias)
     }
 
-    pub fn run_prev_command(&mut self, mut i: i32) -> bool {
+    /// Runs an entry of the history again, the arguments given after the history
+    /// reference being added to it. It is resolved when it runs, so an alias such as
+    /// `alias five !5` always runs whatever is 5th in the history at the time, and the
+    /// command that ran is recorded rather than the reference.
+    #[allow(clippy::needless_return)]
+    pub fn run_prev_command(&mut self, mut i: i32, extra_args: &[String], streams: &mut Streams) -> Status {
         if self.history.is_empty() {
-            println!("You have not ran a command yet.");
-            return true;
+            outln!(streams, "You have not ran a command yet.");
+            return Status::SUCCESS;
         }
         let size: i32 = self.history.len() as i32;
         if i == 0 {
-            println!("There is no zero in the history silly.");
-            return true;
+            outln!(streams, "There is no zero in the history silly.");
+            return Status::SUCCESS;
         }
         let temp_option = if i < 0 {
             self.history.get((size + i) as usize)
@@ -61,77 +519,634 @@ impl Terminal {
             i -= 1;
             self.history.get(i as usize)
         };
-        return if let Some(cmd_option) = temp_option {
-            let (command, user_input) = cmd_option.clone();
-            self.run_command(Some((Command::from(command), String::from(user_input))))
+        return if let Some(entry) = temp_option {
+            if self.rerun_depth >= MAX_RERUN_DEPTH {
+                errln!(streams, "{}: history reference loop", entry.line);
+                return Status::FAILURE;
+            }
+            let mut user_input = entry.line.clone();
+            for arg in extra_args {
+                user_input.push(' ');
+                user_input.push_str(lexer::quote(arg).as_str());
+            }
+            self.rerun_depth += 1;
+            let status = self.run_line(user_input.as_str(), streams);
+            self.rerun_depth -= 1;
+            status
         } else {
-            println!("Invalid index passed.");
-            false
+            errln!(streams, "Invalid index passed.");
+            Status::FAILURE
         };
     }
 
-    // TODO command is not in history from alias
-    pub fn run_command(&mut self, cmd_option: Option<(Command, String)>) -> bool {
-        if let Some((command, user_input)) = cmd_option {
-            let command_wrapper = command.get_command();
-            let suc = command_wrapper.execute(user_input.as_str(), self);
-            if suc && !command_wrapper.name.starts_with("!") {
-                self.append(command, user_input.as_str());
+    /// Runs a line typed by the user once its history references are expanded. A line
+    /// that changed is echoed first, and one ending in `:p` is only printed and recorded.
+    /// The definition of an alias is kept as typed, so an alias such as `alias five !5`
+    /// refers to the history when it runs rather than when it is defined.
+    pub fn run_input(&mut self, user_input: &str, streams: &mut Streams) -> Status {
+        // A Ctrl-C from before the line has nothing left to interrupt
+        jobs::take_interrupt();
+        self.interrupted = false;
+        let defines_alias = user_input.split_whitespace().next() == Some("alias");
+        if defines_alias {
+            return self.run_line(user_input, streams);
+        }
+        let expanded = match history_expansion::expand_history(user_input, self.get_history_commands().as_slice()) {
+            Ok(expanded) => expanded,
+            Err(error) => {
+                errln!(streams, "{}", error);
+                self.last_status = Status::FAILURE;
+                return self.last_status;
             }
-            return suc;
-        } else {
-            eprintln!("Unknown command, try again.")
+        };
+        if !expanded.changed {
+            return self.run_line(user_input, streams);
+        }
+        outln!(streams, "{}", expanded.line.trim());
+        if expanded.print_only {
+            self.record(expanded.line.trim());
+            return Status::SUCCESS;
         }
-        return false;
+        self.run_line(expanded.line.as_str(), streams)
     }
 
-    pub fn parse_user_input(&mut self, user_input: String) -> Option<(Command, String)> {
-        let split_user_input: Vec<String> = user_input.trim().split(" ").map(|v| String::from(v)).collect();
-        if let Some(mut string_command) = split_user_input.first() {
-            let option: Option<&(Command, Option<String>)> = self.aliases.get(string_command);
-            if let Some((cmd, args)) = option {
-                return if let Some(args_string) = args {
-                    Some((Command::from(cmd), format!("{} {}", cmd.to_string(), args_string)))
-                } else {
-                    Some((Command::from(cmd), cmd.to_string()))
+    // Records a line in the history without running it, under the command it starts with
+    fn record(&mut self, line: &str) {
+        let name = match line.split_whitespace().next() {
+            Some(name) => name,
+            None => return,
+        };
+        let command = self.command_named(name);
+        self.append(HistoryEntry::new(command, line, None));
+    }
+
+    /// Parses and runs a full line of user input, recording it in the history along with
+    /// when, where and how it ran once it has run, whether it succeeded or not. A line of
+    /// several pipelines joined by `;`, `&&` and `||` runs them in turn, skipping those the
+    /// status of the last one that ran rules out, and is recorded as a single entry under
+    /// the first command that ran. A line that could not be parsed or whose command was
+    /// not found is recorded under its first word, so it can be fixed with `^old^new`. A
+    /// line re-running a history entry, directly or through an alias, is not recorded
+    /// itself as the entry it runs is.
+    pub fn run_line(&mut self, user_input: &str, streams: &mut Streams) -> Status {
+        let working_dir = self.working_dir.clone();
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs() as i64).unwrap_or(0);
+        let timer = Instant::now();
+        let commands = match self.parse_line(user_input, streams) {
+            // An empty line leaves `$?` as it was
+            Some(command_list) if command_list.items.is_empty() => return self.last_status,
+            Some(command_list) => self.run_list(&command_list, streams),
+            None => Vec::new(),
+        };
+        if commands.iter().any(Command::is_history_invocation) {
+            return self.last_status;
+        }
+        let command = match (commands.first(), user_input.split_whitespace().next()) {
+            (Some(first_command), _) => Command::from(first_command),
+            (None, Some(name)) => self.command_named(name),
+            (None, None) => return self.last_status,
+        };
+        let details = RunDetails { started, working_dir, status: self.last_status.code(), duration: timer.elapsed() };
+        self.append(HistoryEntry::new(command, user_input, Some(details)));
+        self.last_status
+    }
+
+    // Splits a line into tokens, expands its aliases and parses it, reporting what is
+    // wrong with it and setting the status when that fails
+    fn parse_line(&mut self, user_input: &str, streams: &mut Streams) -> Option<CommandList> {
+        let tokens = match lexer::tokenize(user_input) {
+            Ok(tokens) => tokens,
+            Err(error) => {
+                errln!(streams, "{}", error.report(user_input));
+                self.last_status = Status::SYNTAX_ERROR;
+                return None;
+            }
+        };
+        let tokens = match alias::expand_aliases(tokens, &self.aliases) {
+            Ok(tokens) => tokens,
+            Err(error) => {
+                errln!(streams, "{}", error);
+                self.last_status = Status::FAILURE;
+                return None;
+            }
+        };
+        match list::parse(tokens) {
+            Ok(command_list) => Some(command_list),
+            Err(error) => {
+                errln!(streams, "{}", error);
+                self.last_status = Status::SYNTAX_ERROR;
+                None
+            }
+        }
+    }
+
+    // Runs the pipelines of a list in turn, skipping those the status of the last one that
+    // ran rules out, and gives the commands that ran. A subshell stops once `exit` ran and
+    // a function once `return` ran.
+    fn run_list(&mut self, command_list: &CommandList, streams: &mut Streams) -> Vec<Command> {
+        let mut commands: Vec<Command> = Vec::new();
+        for (index, (connector, parsed_pipeline)) in command_list.items.iter().enumerate() {
+            if self.is_stopping() {
+                break;
+            }
+            if !connector.should_run(self.last_status) {
+                continue;
+            }
+            // The status of a pipeline followed by `&&` or `||` only decides what runs next
+            let tested = matches!(command_list.items.get(index + 1), Some((Connector::And | Connector::Or, _)));
+            let in_condition = self.in_condition;
+            self.in_condition |= tested;
+            let ran = self.run_pipeline(parsed_pipeline.clone(), streams);
+            self.in_condition = in_condition;
+            if let Some(ran) = ran {
+                commands.extend(ran);
+            }
+        }
+        commands
+    }
+
+    /// Runs a compound command as a stage of a pipeline. A subshell runs in a copy of the
+    /// shell, leaving the working directory and variables of the shell as they were,
+    /// while everything else runs in the shell itself so a `cd` or an assignment in it
+    /// stays in effect afterwards.
+    pub fn run_compound(&mut self, compound: &Compound, streams: &mut Streams) -> Status {
+        match compound {
+            Compound::Group(command_list) => {
+                self.run_list(command_list, streams);
+                self.last_status
+            }
+            Compound::If { branches, otherwise } => {
+                for (condition, body) in branches {
+                    self.run_condition(condition, streams);
+                    if self.last_status.is_success() {
+                        self.run_list(body, streams);
+                        return self.last_status;
+                    }
+                }
+                match otherwise {
+                    Some(otherwise) => {
+                        self.run_list(otherwise, streams);
+                        self.last_status
+                    }
+                    None => Status::SUCCESS,
+                }
+            }
+            Compound::While { until, condition, body } => {
+                let mut status = Status::SUCCESS;
+                self.loop_depth += 1;
+                loop {
+                    self.run_condition(condition, streams);
+                    match self.loop_step() {
+                        LoopStep::Stop => break,
+                        LoopStep::NextIteration => continue,
+                        LoopStep::Go if self.last_status.is_success() == *until => break,
+                        LoopStep::Go => {}
+                    }
+                    self.run_list(body, streams);
+                    status = self.last_status;
+                    if let LoopStep::Stop = self.loop_step() {
+                        break;
+                    }
                 }
+                self.loop_depth -= 1;
+                self.loop_status(status)
             }
+            Compound::For { name, words, body } => {
+                // Without `in` the loop goes over the positional parameters
+                let mut values: Vec<String> = if words.is_none() { self.positional.clone() } else { Vec::new() };
+                for word in words.iter().flatten() {
+                    match expand::expand_word(word, self) {
+                        Ok(fields) => values.extend(fields),
+                        Err(error) => {
+                            errln!(streams, "{}", error);
+                            return Status::FAILURE;
+                        }
+                    }
+                }
+                let mut status = Status::SUCCESS;
+                self.loop_depth += 1;
+                for value in values {
+                    self.variables.set(name.as_str(), value);
+                    self.run_list(body, streams);
+                    status = self.last_status;
+                    if let LoopStep::Stop = self.loop_step() {
+                        break;
+                    }
+                }
+                self.loop_depth -= 1;
+                self.loop_status(status)
+            }
+            Compound::Case { word, items } => {
+                let text = match expand::expand_to_string(word, self) {
+                    Ok(text) => text,
+                    Err(error) => {
+                        errln!(streams, "{}", error);
+                        return Status::FAILURE;
+                    }
+                };
+                for (patterns, body) in items {
+                    for pattern in patterns {
+                        match expand::expand_pattern(pattern, self) {
+                            Ok(pattern) if glob::is_match(pattern.as_str(), text.as_str()) => {
+                                self.run_list(body, streams);
+                                return self.last_status;
+                            }
+                            Ok(_) => {}
+                            Err(error) => {
+                                errln!(streams, "{}", error);
+                                return Status::FAILURE;
+                            }
+                        }
+                    }
+                }
+                Status::SUCCESS
+            }
+            Compound::Function { name, body, text } => {
+                self.define_function(name.as_str(), Function { body: (**body).clone(), text: text.clone() });
+                Status::SUCCESS
+            }
+            Compound::Subshell(command_list) => {
+                let mut subshell = self.subshell();
+                subshell.run_list(command_list, streams);
+                // The subshell shares the process, so a `cd` in it has to be undone
+                if subshell.working_dir != self.working_dir {
+                    if let Err(error) = env::set_current_dir(&self.working_dir) {
+                        errln!(streams, "Failed to return to {}: {}", self.working_dir, error);
+                    }
+                }
+                subshell.exit_status.or(subshell.return_status).unwrap_or(subshell.last_status)
+            }
+        }
+    }
 
-            // Correctly handle command if statement order matters
-            let cmd_option: Option<Command> = if string_command.starts_with("!!") {
-                Command::get_command_enum("!!")
-            } else if string_command.starts_with("!-") {
-                Command::get_command_enum("!-")
-            } else if string_command.starts_with("!") {
-                Command::get_command_enum("!")
-            } else {
-                Command::get_command_enum(string_command.trim())
+    /// Runs a function with the arguments it was called with as its positional
+    /// parameters. The variables it made local and the positional parameters of its
+    /// caller are put back once it returns.
+    pub fn call_function(&mut self, name: &str, args: Vec<String>, streams: &mut Streams) -> Status {
+        let body = match self.functions.get(name) {
+            Some(function) => function.body.clone(),
+            None => {
+                errln!(streams, "{}: function not found", name);
+                return Status::NOT_FOUND;
+            }
+        };
+        if self.frames.len() >= MAX_FUNCTION_DEPTH {
+            errln!(streams, "{}: functions nested more than {} deep", name, MAX_FUNCTION_DEPTH);
+            return Status::FAILURE;
+        }
+        let caller_positional = std::mem::replace(&mut self.positional, args);
+        // `break` and `continue` only leave the loops of the function itself
+        let caller_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        self.frames.push(Vec::new());
+        let status = self.run_compound(&body, streams);
+        for (name, variable) in self.frames.pop().unwrap_or_default().into_iter().rev() {
+            self.variables.restore(name.as_str(), variable);
+        }
+        self.positional = caller_positional;
+        self.loop_depth = caller_loop_depth;
+        self.return_status.take().unwrap_or(status)
+    }
+
+    /// Makes a variable local to the function running, setting it to the given value or
+    /// to an empty one. Returns false when no function is running.
+    pub fn declare_local(&mut self, name: &str, value: Option<String>) -> bool {
+        let frame = match self.frames.last_mut() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        if !frame.iter().any(|(local, _)| local == name) {
+            frame.push((String::from(name), self.variables.get_variable(name).cloned()));
+        }
+        self.variables.set(name, value.unwrap_or_default());
+        true
+    }
+
+    /// Ends the function running once the command running in it finished, with the given
+    /// status. Returns false when no function is running.
+    pub fn return_from_function(&mut self, status: Status) -> bool {
+        if self.frames.is_empty() {
+            return false;
+        }
+        self.return_status = Some(status);
+        true
+    }
+
+    /// Leaves loops once the command running in them finished, as many as asked for or
+    /// every loop running when there are fewer. Returns false when no loop is running.
+    pub fn leave_loops(&mut self, control: LoopControl) -> bool {
+        if self.loop_depth == 0 {
+            return false;
+        }
+        self.loop_control = Some(match control {
+            LoopControl::Break(levels) => LoopControl::Break(levels.min(self.loop_depth)),
+            LoopControl::Continue(levels) => LoopControl::Continue(levels.min(self.loop_depth)),
+        });
+        true
+    }
+
+    // Whether `exit` ran in a subshell, `return` in a function or `break` or `continue`
+    // in a loop, so nothing else is to run in it, or Ctrl-C interrupted the line
+    fn is_stopping(&self) -> bool {
+        self.exit_status.is_some() || self.return_status.is_some() || self.loop_control.is_some() || self.interrupted
+    }
+
+    // Expands, resolves and runs a pipeline of a list, giving the commands of its stages
+    // when it ran. A pipeline of only assignments sets shell variables instead.
+    fn run_pipeline(&mut self, parsed_pipeline: Pipeline, streams: &mut Streams) -> Option<Vec<Command>> {
+        let single_stage = parsed_pipeline.stages.len() == 1;
+        let mut background = parsed_pipeline.background;
+        if background && parsed_pipeline.stages.iter().any(|stage| stage.compound.is_some()) {
+            errln!(streams, "Subshells and groups cannot run in the background, running \"{}\" in the foreground",
+                   parsed_pipeline.text);
+            background = false;
+        }
+        let mut stages: Vec<(Option<Command>, Stage)> = Vec::new();
+        let mut commands: Vec<Command> = Vec::new();
+        self.substitution_status = None;
+        for stage in parsed_pipeline.stages {
+            let stage = match self.expand_stage(stage, streams) {
+                Some(stage) => stage,
+                None => {
+                    self.last_status = Status::FAILURE;
+                    return None;
+                }
             };
-            return if let Some(command) = cmd_option {
-                Some((command, user_input))
-            } else {
+            if let Some(compound) = &stage.compound {
+                commands.extend(self.compound_command(compound));
+                stages.push((None, stage));
+                continue;
+            }
+            if stage.words.is_empty() && single_stage {
+                for (name, value) in stage.assignments {
+                    self.variables.set(name.as_str(), value);
+                }
+                self.last_status = self.substitution_status.unwrap_or(Status::SUCCESS);
+                return None;
+            }
+            match self.resolve_stage(stage, streams) {
+                Some((command, stage)) => {
+                    commands.push(Command::from(&command));
+                    stages.push((Some(command), stage));
+                }
+                None => {
+                    self.last_status = Status::NOT_FOUND;
+                    return None;
+                }
+            }
+        }
+        pipeline::run(stages, background, parsed_pipeline.text.as_str(), self, streams);
+        Some(commands)
+    }
+
+    /// Runs the command of a `$(...)` in a subshell and gives what it wrote to stdout,
+    /// without its trailing newlines. Its status becomes `$?`, which is also the status
+    /// of a line of only assignments.
+    pub fn substitute(&mut self, command: &str) -> Result<String, String> {
+        let tokens = lexer::tokenize(command).map_err(|error| format!("$({}): {}", command, error))?;
+        let tokens = alias::expand_aliases(tokens, &self.aliases)?;
+        let command_list = list::parse(tokens).map_err(|error| format!("$({}): {}", command, error))?;
+        let stdout = Output::buffer();
+        let mut streams = Streams {
+            stdin: Input::Inherit,
+            stdout: stdout.try_clone().map_err(|error| error.to_string())?,
+            stderr: Output::Stderr,
+        };
+        // Its status is only tested, as in `x=$(grep ...) || ...`
+        let in_condition = std::mem::replace(&mut self.in_condition, true);
+        let status = self.run_compound(&Compound::Subshell(command_list), &mut streams);
+        self.in_condition = in_condition;
+        self.last_status = status;
+        self.substitution_status = Some(status);
+        let output = String::from_utf8_lossy(&stdout.take_bytes()).into_owned();
+        Ok(String::from(output.trim_end_matches('\n')))
+    }
+
+    // Runs the condition of an if, while or until, which sets the status it is tested by
+    fn run_condition(&mut self, condition: &CommandList, streams: &mut Streams) {
+        let in_condition = std::mem::replace(&mut self.in_condition, true);
+        self.run_list(condition, streams);
+        self.in_condition = in_condition;
+    }
+
+    // The status a loop ends with, given the status of the last command of its body. A
+    // loop Ctrl-C interrupted ends interrupted.
+    fn loop_status(&self, status: Status) -> Status {
+        if self.last_status.signal() == Some(libc::SIGINT) { self.last_status } else { status }
+    }
+
+    // What a loop does once its condition or body ran. It stops when `exit`, `return` or
+    // `break` ran, when Ctrl-C interrupted the last command or the shell itself, or when
+    // the last command wrote to a pipe nothing reads anymore, as in
+    // `while true; do echo y; done | head`. `continue` skips to the next iteration.
+    fn loop_step(&mut self) -> LoopStep {
+        if jobs::take_interrupt() {
+            self.interrupted = true;
+            self.last_status = Status::signaled(libc::SIGINT);
+        }
+        match self.loop_control.take() {
+            Some(LoopControl::Break(1)) => LoopStep::Stop,
+            Some(LoopControl::Continue(1)) => LoopStep::NextIteration,
+            // The loops around this one have yet to be left
+            Some(LoopControl::Break(levels)) => {
+                self.loop_control = Some(LoopControl::Break(levels - 1));
+                LoopStep::Stop
+            }
+            Some(LoopControl::Continue(levels)) => {
+                self.loop_control = Some(LoopControl::Continue(levels - 1));
+                LoopStep::Stop
+            }
+            None if self.is_stopping() || matches!(self.last_status.signal(), Some(libc::SIGINT | libc::SIGPIPE)) => {
+                LoopStep::Stop
+            }
+            None => LoopStep::Go,
+        }
+    }
+
+    // The first command of a compound command, which a line starting with it is recorded
+    // under. A function definition is recorded under the function it defines, and a
+    // compound of only assignments has none.
+    fn compound_command(&self, compound: &Compound) -> Option<Command> {
+        if let Compound::Function { name, .. } = compound {
+            return Some(Command::Function(name.clone()));
+        }
+        for (_, parsed_pipeline) in compound.lists().into_iter().flat_map(|command_list| &command_list.items) {
+            for stage in &parsed_pipeline.stages {
+                let command = match (&stage.compound, stage.words.first()) {
+                    (Some(inner), _) => self.compound_command(inner),
+                    (None, Some(word)) => Some(self.command_named(word.to_unexpanded_string().as_str())),
+                    (None, None) => None,
+                };
+                if command.is_some() {
+                    return command;
+                }
+            }
+        }
+        None
+    }
+
+    // The command a name refers to, a program that cannot be found included
+    fn command_named(&self, name: &str) -> Command {
+        self.resolve_command(name).unwrap_or_else(|| Command::External(String::from(name)))
+    }
+
+    /// Prints a notice for every background job that finished since the last check.
+    pub fn report_finished_jobs(&mut self) {
+        for job in self.jobs.reap() {
+            eprintln!("[{}]   {:<22}{}", job.id, jobs::describe_status(job.last_status()), job.command_line);
+        }
+    }
+
+    /// Expands the words of a parsed pipeline stage.
+    fn expand_stage(&mut self, stage: Stage<Word>, streams: &mut Streams) -> Option<Stage> {
+        match expand::expand_stage(stage, self) {
+            Ok(stage) => Some(stage),
+            Err(error) => {
+                errln!(streams, "{}", error);
                 None
-            };
+            }
         }
+    }
 
-        return None;
+    /// Resolves the command of a single expanded pipeline stage.
+    fn resolve_stage(&self, stage: Stage, streams: &mut Streams) -> Option<(Command, Stage)> {
+        let string_command = match stage.words.first() {
+            Some(string_command) => string_command,
+            None => {
+                errln!(streams, "Syntax error: expected a command on both sides of \"|\"");
+                return None;
+            }
+        };
+        let cmd_option: Option<Command> = self.resolve_command(string_command);
+        if cmd_option.is_none() {
+            errln!(streams, "Unknown command \"{}\", try again.", string_command);
+        }
+        cmd_option.map(|command| (command, stage))
     }
 
-    pub fn remove_command_aliases(&mut self, command: Command) -> Vec<String> {
-        let aliases_to_remove: Vec<String> = self.aliases.iter()
-            .filter(|(_, (cmd, _))| *cmd == command)
-            .map(|(key, (_, _))| key)
-            .cloned()
-            .collect();
+    /// Finds the command a name refers to, looking through the functions, the builtins and
+    /// then the programs on the system path.
+    pub fn resolve_command(&self, string_command: &str) -> Option<Command> {
+        // A function hides a builtin or program of the same name
+        if self.functions.contains_key(string_command) {
+            return Some(Command::Function(String::from(string_command)));
+        }
+        // Correctly handle command if statement order matters
+        let cmd_option: Option<Command> = if string_command.starts_with("!!") {
+            Command::get_command_enum("!!")
+        } else if string_command.starts_with("!-") {
+            Command::get_command_enum("!-")
+        } else if string_command.starts_with("!") {
+            Command::get_command_enum("!")
+        } else {
+            Command::get_command_enum(string_command)
+        };
+        // Anything that is not a builtin may still be a program on the system path
+        cmd_option.or_else(|| {
+            self.find_executable(string_command)
+                .map(|_| Command::External(String::from(string_command)))
+        })
+    }
+
+    /// Removes the alias with the given name or, when there is none, every alias whose
+    /// command is the given name.
+    pub fn remove_command_aliases(&mut self, name: &str) -> Vec<String> {
+        let aliases_to_remove: Vec<String> = if self.aliases.contains_key(name) {
+            vec![String::from(name)]
+        } else {
+            self.aliases.iter()
+                .filter(|(_, text)| alias_command(text) == Some(String::from(name)))
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
         for alias in &aliases_to_remove {
             self.aliases.remove(alias);
         }
+        if !aliases_to_remove.is_empty() {
+            self.save_aliases();
+        }
 
         aliases_to_remove
     }
 
+    /// Resolves a program name to an executable, searching each entry of the colon
+    /// separated system path. Names containing a `/` are resolved against the working
+    /// directory instead.
+    pub fn find_executable(&self, program: &str) -> Option<PathBuf> {
+        if program.is_empty() {
+            return None;
+        }
+        if program.contains('/') {
+            let path = Path::new(&self.working_dir).join(program);
+            return if is_executable(&path) { Some(path) } else { None };
+        }
+        self.system_path().split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| Path::new(&self.working_dir).join(dir).join(program))
+            .find(|path| is_executable(path))
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata(path).map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_executable(path: &Path) -> bool {
+    metadata(path).map(|metadata| metadata.is_file()).unwrap_or(false)
 }
 
-fn get_format(key: String, cmd: String, args: &Option<String>) -> String {
-    format!("\"{}\" => {} {}", key, cmd.to_string(), args.clone().unwrap_or(String::from("")))
-}
\ No newline at end of file
+fn get_format(key: &str, text: &str) -> String {
+    format!("\"{}\" => {}", key, text)
+}
+
+// The command an alias runs, the first word of its text
+fn alias_command(text: &str) -> Option<String> {
+    match lexer::tokenize(text).ok()?.into_iter().next()? {
+        Token::Word(word) => Some(word.to_unexpanded_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Streams collecting what is written, so it can be checked
+    fn buffers() -> Streams {
+        Streams { stdin: Input::Inherit, stdout: Output::buffer(), stderr: Output::buffer() }
+    }
+
+    #[test]
+    fn stops_a_history_reference_loop() {
+        let mut terminal = Terminal::new(String::from("/"), String::from("/bin"));
+        let mut streams = buffers();
+        terminal.run_line("foo", &mut streams);
+        terminal.add_alias(String::from("foo"), String::from("!1"));
+        assert_eq!(terminal.run_line("foo", &mut streams).code(), Status::FAILURE.code());
+        let errors = String::from_utf8(streams.stderr.take_bytes()).unwrap();
+        assert!(errors.ends_with("foo: history reference loop\n"), "{}", errors);
+    }
+
+    #[test]
+    fn finds_aliases_whose_command_is_gone() {
+        let mut terminal = Terminal::new(String::from("/"), String::from("/bin"));
+        terminal.run_line("f() { cd /; }", &mut buffers());
+        for (name, text) in [("builtin", "cd /tmp"), ("function", "f"), ("alias", "builtin"), ("history", "!5"),
+                             ("group", "{ cd; }"), ("assignment", "x=1 cd"), ("expanded", "$EDITOR x"),
+                             ("gone", "no-such-command-here -x"), ("quoted", "'gone too'")] {
+            terminal.add_alias(String::from(name), String::from(text));
+        }
+        assert_eq!(terminal.aliases_without_command(), [(String::from("gone"), String::from("no-such-command-here"))]);
+    }
+
+    #[test]
+    fn records_a_definition_under_its_function() {
+        let mut terminal = Terminal::new(String::from("/"), String::from("/bin"));
+        terminal.run_line("f() { local x=1; echo $x; }", &mut buffers());
+        let entry = terminal.history.last().unwrap();
+        assert!(matches!(&entry.command, Command::Function(name) if name == "f"));
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::env;
+
+/// A shell variable, passed on to the programs the shell runs once it is exported.
+#[derive(Clone)]
+pub(crate) struct Variable {
+    pub value: String,
+    pub exported: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct Variables {
+    variables: HashMap<String, Variable>,
+}
+
+impl Variables {
+    /// Starts with every variable of the shell's own environment, all of them exported.
+    pub fn from_env() -> Variables {
+        let variables = env::vars()
+            .map(|(name, value)| (name, Variable { value, exported: true }))
+            .collect();
+        Variables { variables }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(|variable| variable.value.as_str())
+    }
+
+    pub fn get_variable(&self, name: &str) -> Option<&Variable> {
+        self.variables.get(name)
+    }
+
+    /// Sets the value of a variable, an existing variable stays exported if it was.
+    pub fn set(&mut self, name: &str, value: String) {
+        match self.variables.get_mut(name) {
+            Some(variable) => variable.value = value,
+            None => {
+                self.variables.insert(String::from(name), Variable { value, exported: false });
+            }
+        }
+    }
+
+    /// Marks a variable as exported, setting its value when one is given. Exporting a
+    /// variable that does not exist yet creates it empty.
+    pub fn export(&mut self, name: &str, value: Option<String>) {
+        let variable = self.variables.entry(String::from(name))
+            .or_insert_with(|| Variable { value: String::new(), exported: true });
+        variable.exported = true;
+        if let Some(value) = value {
+            variable.value = value;
+        }
+    }
+
+    pub fn unset(&mut self, name: &str) -> Option<Variable> {
+        self.variables.remove(name)
+    }
+
+    /// Puts back a variable as it was before it was temporarily replaced, None meaning
+    /// it did not exist.
+    pub fn restore(&mut self, name: &str, variable: Option<Variable>) {
+        match variable {
+            Some(variable) => self.variables.insert(String::from(name), variable),
+            None => self.variables.remove(name),
+        };
+    }
+
+    /// Every variable, sorted by name.
+    pub fn all(&self) -> Vec<(&String, &Variable)> {
+        let mut all: Vec<(&String, &Variable)> = self.variables.iter().collect();
+        all.sort_by_key(|(name, _)| *name);
+        all
+    }
+
+    /// The environment handed to spawned programs, sorted by name.
+    pub fn exported(&self) -> Vec<(&String, &String)> {
+        self.all().into_iter()
+            .filter(|(_, variable)| variable.exported)
+            .map(|(name, variable)| (name, &variable.value))
+            .collect()
+    }
+}
+
+/// Whether a name can be used for a variable: letters, digits and underscores, not
+/// starting with a digit.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
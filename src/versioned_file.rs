@@ -0,0 +1,81 @@
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A file of the shell's own starting with a `#rust-terminal <kind> v<version>` header,
+/// the version being bumped whenever the layout of the lines after it changes.
+pub(crate) struct VersionedFile {
+    path: PathBuf,
+    // What the file holds, such as `aliases`
+    kind: &'static str,
+}
+
+impl VersionedFile {
+    pub fn new(path: PathBuf, kind: &'static str) -> VersionedFile {
+        VersionedFile { path, kind }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Reads the version of the file and the lines after its header, or None when there
+    /// is no file yet. A file with a version outside of the given ones, such as a file
+    /// written by a newer version, is rejected.
+    pub fn read(&self, versions: RangeInclusive<u32>) -> io::Result<Option<(u32, Vec<String>)>> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => self.parse(contents.as_str(), versions).map(Some),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Splits contents read from the file into its version and the lines after its
+    /// header, for files that must be read some other way, such as under a lock.
+    pub fn parse(&self, contents: &str, versions: RangeInclusive<u32>) -> io::Result<(u32, Vec<String>)> {
+        let mut lines = contents.lines();
+        let version = lines.next()
+            .and_then(|header| header.strip_prefix(self.header_prefix().as_str()))
+            .and_then(|version| version.parse::<u32>().ok())
+            .filter(|version| versions.contains(version));
+        match version {
+            Some(version) => Ok((version, lines.map(String::from).collect())),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("{} is not a version {} {} file", self.path.display(), versions.end(), self.kind))),
+        }
+    }
+
+    /// Replaces the file with the header of the given version followed by the lines.
+    pub fn write(&self, version: u32, lines: &[String]) -> io::Result<()> {
+        let mut output = self.header(version);
+        for line in lines {
+            output.push_str(line);
+            output.push('\n');
+        }
+        replace(self.path.as_path(), output.as_str())
+    }
+
+    /// The header line of the given version, with its line break.
+    pub fn header(&self, version: u32) -> String {
+        format!("{}{}\n", self.header_prefix(), version)
+    }
+
+    fn header_prefix(&self) -> String {
+        format!("#rust-terminal {} v", self.kind)
+    }
+}
+
+/// Writes the contents to a temporary file next to the file and renames it into place, so
+/// a crash or a failed write never leaves a truncated file behind.
+pub(crate) fn replace(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temp_path = path.to_path_buf().into_os_string();
+    // Unique per shell so two shells never write the same temporary file
+    temp_path.push(format!(".{}.tmp", process::id()));
+    let written = fs::write(&temp_path, contents).and_then(|_| fs::rename(&temp_path, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}